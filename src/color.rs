@@ -0,0 +1,102 @@
+use super::*;
+
+/// Converts a hue in degrees (`[0, 360)`, wrapped if outside) plus saturation and value in
+/// `[0, 1]` to linear RGB.
+pub fn hsv_to_rgb(h: f64, s: f64, v: f64) -> Vector3 {
+	let h = h.rem_euclid(360.0) / 60.0;
+	let chroma = v * s;
+	let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+
+	let (r, g, b) = match h as u32 {
+		0 => (chroma, x, 0.0),
+		1 => (x, chroma, 0.0),
+		2 => (0.0, chroma, x),
+		3 => (0.0, x, chroma),
+		4 => (x, 0.0, chroma),
+		_ => (chroma, 0.0, x),
+	};
+
+	let m = v - chroma;
+	Vector3::new(r + m, g + m, b + m)
+}
+
+/// The piecewise sRGB transfer function, linear near black and a 2.4 power curve above.
+pub fn linear_to_srgb(c: f64) -> f64 {
+	if c <= 0.003_130_8 {
+		c * 12.92
+	} else {
+		1.055 * c.powf(1.0 / 2.4) - 0.055
+	}
+}
+
+/// How colors are encoded when they are written out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ColorSpace {
+	/// Raw linear values.
+	#[default]
+	Linear,
+	/// Encoded with `linear_to_srgb`, what most image viewers expect.
+	Srgb,
+}
+
+impl ColorSpace {
+	pub fn encode(&self, color: Vector3) -> Vector3 {
+		match self {
+			ColorSpace::Linear => color,
+			ColorSpace::Srgb => color.map(|c| linear_to_srgb(c.max(0.0))),
+		}
+	}
+}
+
+/// Reinhard tone mapping, compresses `[0, inf)` into `[0, 1)` per channel.
+pub fn tone_map_reinhard(c: Vector3) -> Vector3 {
+	c.map(|c| c.max(0.0) / (1.0 + c.max(0.0)))
+}
+
+/// Extended Reinhard with a white point: `white` and everything brighter maps to 1.0, so
+/// highlights keep their headroom instead of being squashed towards gray.
+pub fn tone_map_reinhard_white(c: Vector3, white: f64) -> Vector3 {
+	c.map(|c| {
+		let c = c.max(0.0);
+		(c * (1.0 + c / (white * white)) / (1.0 + c)).min(1.0)
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn close(a: Vector3, b: Vector3) -> bool {
+		(a - b).magnitude() < 1e-9
+	}
+
+	#[test]
+	fn primary_hues_are_pure_colors() {
+		assert!(close(hsv_to_rgb(0.0, 1.0, 1.0), Vector3::new(1.0, 0.0, 0.0)));
+		assert!(close(hsv_to_rgb(120.0, 1.0, 1.0), Vector3::new(0.0, 1.0, 0.0)));
+		assert!(close(hsv_to_rgb(360.0, 1.0, 1.0), Vector3::new(1.0, 0.0, 0.0)));
+		assert!(close(hsv_to_rgb(120.0, 0.0, 0.5), Vector3::new(0.5, 0.5, 0.5)));
+	}
+
+	#[test]
+	fn srgb_is_continuous_at_the_linear_segment_boundary() {
+		let edge = 0.003_130_8;
+
+		assert!((linear_to_srgb(edge) - edge * 12.92).abs() < 1e-12);
+		assert!((linear_to_srgb(edge + 1e-9) - linear_to_srgb(edge)).abs() < 1e-6);
+		assert_eq!(linear_to_srgb(0.0), 0.0);
+		assert!((linear_to_srgb(1.0) - 1.0).abs() < 1e-12);
+	}
+
+	#[test]
+	fn white_point_maps_to_one() {
+		let white = 4.0;
+
+		assert!(close(tone_map_reinhard_white(Vector3::new(white, white, white), white), Vector3::new(1.0, 1.0, 1.0)));
+		assert!(close(tone_map_reinhard_white(Vector3::new(10.0, 10.0, 10.0), white), Vector3::new(1.0, 1.0, 1.0)));
+
+		// mid-tones come out a little brighter than plain Reinhard's 0.5
+		let mid = tone_map_reinhard_white(Vector3::new(1.0, 1.0, 1.0), white);
+		assert!((mid.x - 0.531_25).abs() < 1e-9);
+	}
+}
@@ -1,9 +1,13 @@
 const PI: f64 = std::f64::consts::PI;
 const EPSILON: f64 = 0.001;
+/// Default distance secondary rays are pushed off the surface they start on.
+/// A few epsilons is enough for the built-in scene, other scenes may need more.
+pub const SURFACE_BIAS: f64 = 4.0 * EPSILON;
 
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Vector3 {
 	x: f64,
 	y: f64,
@@ -23,6 +27,15 @@ impl Vector3 {
 		(self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
 	}
 
+	/// `magnitude()` squared, without the square root.
+	pub fn length_squared(&self) -> f64 {
+		self.x * self.x + self.y * self.y + self.z * self.z
+	}
+
+	pub fn max_component(&self) -> f64 {
+		self.x.max(self.y).max(self.z)
+	}
+
 	pub fn map(&self, f: impl Fn(f64) -> f64) -> Self {
 		Vector3 {
 			x: f(self.x),
@@ -34,9 +47,64 @@ impl Vector3 {
 	pub fn dot(&self, rhs: Vector3) -> f64 {
 		self.x *  rhs.x + self.y * rhs.y + self.z * rhs.z
 	}
+
+	/// Linear interpolation, `self` at `t = 0.0` and `other` at `t = 1.0`.
+	pub fn lerp(&self, other: Vector3, t: f64) -> Vector3 {
+		*self + (other - *self) * t
+	}
+
+	pub fn cross(&self, rhs: Vector3) -> Vector3 {
+		Vector3::new(
+			self.y * rhs.z - self.z * rhs.y,
+			self.z * rhs.x - self.x * rhs.z,
+			self.x * rhs.y - self.y * rhs.x,
+		)
+	}
+
+	/// Mirrors an incoming direction about `normal`, e.g. a view ray about the surface it hits.
+	/// `normal` has to be unit length, otherwise the result is scaled and skewed.
+	pub fn reflect(&self, normal: Vector3) -> Vector3 {
+		*self - normal * 2.0 * self.dot(normal)
+	}
+
+	/// Bends an incoming unit direction through a surface with unit `normal` facing against it,
+	/// `eta` being the ratio of refractive indices `n_from / n_to`. `None` on total internal
+	/// reflection.
+	pub fn refract(&self, normal: Vector3, eta: f64) -> Option<Vector3> {
+		let cos_i = -self.dot(normal).clamp(-1.0, 1.0);
+		let k = 1.0 - eta * eta * (1.0 - cos_i * cos_i);
+		if k < 0.0 {
+			return None;
+		}
+
+		Some(*self * eta + normal * (eta * cos_i - k.sqrt()))
+	}
+
+	/// Rotates by `angle` radians around `axis` through the origin with Rodrigues' formula,
+	/// counter-clockwise when looking down `axis`. `axis` need not be unit length.
+	pub fn rotate_around_axis(&self, axis: Vector3, angle: f64) -> Vector3 {
+		let k = axis.normalize();
+		let (sin, cos) = angle.sin_cos();
+
+		*self * cos + k.cross(*self) * sin + k * (k.dot(*self) * (1.0 - cos))
+	}
 }
 
 #[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Vector2 {
+	x: f64,
+	y: f64,
+}
+
+impl Vector2 {
+	pub fn new(x: f64, y: f64) -> Self {
+		Vector2 { x, y }
+	}
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
 struct U8Color {
 	r: u8,
 	g: u8,
@@ -45,6 +113,8 @@ struct U8Color {
 }
 
 impl U8Color {
+	pub const TRANSPARENT: U8Color = U8Color { r: 0, g: 0, b: 0, a: 0 };
+
 	pub fn as_u32(self) -> u32 {
 		self.b as u32 | ((self.g as u32) << 8) | ((self.r as u32) << 16) | ((self.a as u32) << 24)
 	}
@@ -54,13 +124,21 @@ impl U8Color {
 			r: (from.x * 255.0) as u8,
 			g: (from.y * 255.0) as u8,
 			b: (from.z * 255.0) as u8,
-			a: a
+			a,
 		}
 	}
+
+	/// Packs `color` with premultiplied alpha, `coverage` being the fraction of the pixel
+	/// covered by geometry. Fully covered pixels get an alpha of 255, misses should use
+	/// `U8Color::TRANSPARENT`.
+	pub fn premultiplied(color: Vector3, coverage: f64) -> Self {
+		let coverage = coverage.clamp(0.0, 1.0);
+		Self::from_vec(color.map(|v| v.clamp(0.0, 1.0)) * coverage, (coverage * 255.0) as u8)
+	}
 }
 
 
-use std::ops::{Add, Div, Mul, Sub};
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
 
 impl Add for Vector3 {
 	type Output = Self;
@@ -86,6 +164,22 @@ impl Sub for Vector3 {
 	}
 }
 
+impl AddAssign for Vector3 {
+	fn add_assign(&mut self, rhs: Vector3) {
+		self.x += rhs.x;
+		self.y += rhs.y;
+		self.z += rhs.z;
+	}
+}
+
+impl SubAssign for Vector3 {
+	fn sub_assign(&mut self, rhs: Vector3) {
+		self.x -= rhs.x;
+		self.y -= rhs.y;
+		self.z -= rhs.z;
+	}
+}
+
 impl Div<f64> for Vector3 {
 	type Output = Self;
 
@@ -102,6 +196,31 @@ impl Mul<f64> for Vector3 {
 	}
 }
 
+/// Component-wise product, e.g. for tinting a surface color by a light color.
+impl Mul<Vector3> for Vector3 {
+	type Output = Self;
+
+	fn mul(self, rhs: Vector3) -> Self::Output {
+		Vector3::new(self.x * rhs.x, self.y * rhs.y, self.z * rhs.z)
+	}
+}
+
+impl Mul<Vector3> for f64 {
+	type Output = Vector3;
+
+	fn mul(self, rhs: Vector3) -> Self::Output {
+		rhs.map(|v| self * v)
+	}
+}
+
+impl Neg for Vector3 {
+	type Output = Self;
+
+	fn neg(self) -> Self::Output {
+		self.map(|v| -v)
+	}
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Ray {
 	origin: Vector3,
@@ -112,26 +231,131 @@ impl Ray {
 	pub fn new(origin: Vector3, direction: Vector3) -> Self {
 		Ray { origin, direction }
 	}
+
+	/// Starts a secondary (shadow, reflection or refraction) ray at the surface point `pos`.
+	/// The origin is offset by `bias` along `normal`, otherwise the ray immediately hits the
+	/// surface it starts on and produces acne. Refracted rays travel into the surface, so they
+	/// are offset against the normal instead.
+	pub fn from_surface(pos: Vector3, normal: Vector3, direction: Vector3, bias: f64) -> Self {
+		let side = if direction.dot(normal) < 0.0 { -1.0 } else { 1.0 };
+		Ray::new(pos + normal * (bias * side), direction)
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Camera {
+	pub position: Vector3,
+	/// Unit direction the camera looks in.
+	pub forward: Vector3,
+	/// Rough direction of the top of the image, it doesn't have to be perpendicular to
+	/// `forward` but must not be parallel to it.
+	pub up: Vector3,
+	/// Vertical field of view in degrees.
+	pub fov: f64,
 }
 
-fn generate_primary_ray((width, height): (usize, usize), (x, y): (usize, usize), fov: f64) -> Ray {
+impl Camera {
+	pub fn new(position: Vector3, forward: Vector3, up: Vector3, fov: f64) -> Self {
+		Camera { position, forward: forward.normalize(), up, fov }
+	}
+
+	/// A camera at `position` looking at `target`, with +y up.
+	pub fn look_at(position: Vector3, target: Vector3, fov: f64) -> Self {
+		Camera::new(position, target - position, Vector3::new(0.0, 1.0, 0.0), fov)
+	}
+
+	/// Unit vector pointing to the right of the image.
+	pub fn right(&self) -> Vector3 {
+		self.up.cross(self.forward).normalize()
+	}
+
+	/// Unit vector pointing to the top of the image, `up` made perpendicular to `forward`.
+	pub fn true_up(&self) -> Vector3 {
+		self.forward.cross(self.right())
+	}
+
+	/// Turns the camera by `yaw` radians around +y and `pitch` radians up, with the pitch
+	/// clamped short of straight up or down so the view never flips over. Resets `up` to +y.
+	pub fn turn(&self, yaw: f64, pitch: f64) -> Camera {
+		let limit = PI / 2.0 - 0.01;
+		let yaw = self.forward.x.atan2(self.forward.z) + yaw;
+		let pitch = (self.forward.y.clamp(-1.0, 1.0).asin() + pitch).clamp(-limit, limit);
+
+		let forward = Vector3::new(yaw.sin() * pitch.cos(), pitch.sin(), yaw.cos() * pitch.cos());
+		Camera::new(self.position, forward, Vector3::new(0.0, 1.0, 0.0), self.fov)
+	}
+
+	/// Unit direction through the point `(px, py)` of the image plane one unit in front of the
+	/// camera, `px` pointing right and `py` up.
+	fn direction_through(&self, px: f64, py: f64) -> Vector3 {
+		(self.right() * px + self.true_up() * py + self.forward).normalize()
+	}
+
+	/// Unit directions through the top left, top right, bottom right and bottom left corners of
+	/// an image with the given aspect ratio (width / height), matching `generate_primary_ray`.
+	pub fn frustum_corners(&self, aspect: f64) -> [Vector3; 4] {
+		let half_height = f64::tan(self.fov / 2.0 * PI / 180.0);
+		let half_width = half_height * aspect;
+
+		[
+			self.direction_through(-half_width, half_height),
+			self.direction_through(half_width, half_height),
+			self.direction_through(half_width, -half_height),
+			self.direction_through(-half_width, -half_height),
+		]
+	}
+
+	/// Stores the camera as JSON so a good view can be restored later with `Camera::load`.
+	#[cfg(feature = "serde")]
+	pub fn save(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+		let file = std::fs::File::create(path)?;
+		serde_json::to_writer_pretty(file, self)?;
+		Ok(())
+	}
+
+	#[cfg(feature = "serde")]
+	pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Camera> {
+		let file = std::fs::File::open(path)?;
+		Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+	}
+}
+
+impl Default for Camera {
+	fn default() -> Self {
+		Camera::new(Vector3::new(0.0, 0.0, -10.0), Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.0, 1.0, 0.0), 64.0)
+	}
+}
+
+fn generate_primary_ray(camera: &Camera, size: (usize, usize), pixel: (usize, usize)) -> Ray {
+	generate_primary_ray_at(camera, size, pixel, (0.5, 0.5))
+}
+
+/// Like `generate_primary_ray`, but through the point `offset` (each in `[0, 1)`, from the top
+/// left corner) of the pixel instead of its center.
+fn generate_primary_ray_at(camera: &Camera, (width, height): (usize, usize), (x, y): (usize, usize), (ox, oy): (f64, f64)) -> Ray {
 	let width = width as f64;
 	let height = height as f64;
 	let aspect = width / height;
 	let x = x as f64;
 	let y = y as f64;
+	let fov = camera.fov;
 
-	let px = (2.0 * ((x + 0.5) / width) - 1.0) * f64::tan(fov / 2.0 * PI / 180.0) * aspect;
-	let py = (1.0 - 2.0 * ((y + 0.5) / height)) * f64::tan(fov / 2.0 * PI / 180.0);
+	let px = (2.0 * ((x + ox) / width) - 1.0) * f64::tan(fov / 2.0 * PI / 180.0) * aspect;
+	let py = (1.0 - 2.0 * ((y + oy) / height)) * f64::tan(fov / 2.0 * PI / 180.0);
 
 	Ray::new(
-		Vector3::new(0.0, 0.0, -10.0),
-		Vector3::new(px, py, 1.0).normalize(),
+		camera.position,
+		camera.direction_through(px, py),
 	)
 }
 
 pub mod sdf {
 	use super::*;
+	use render::Material;
+
+	/// Distance reported by an empty scene, far beyond anything a ray will march to.
+	pub const NO_SURFACE: f64 = 1.0e9;
 
 
 	pub fn sphere(radius: f64) -> impl Fn(Vector3) -> f64 {
@@ -140,12 +364,177 @@ pub mod sdf {
 		}
 	}
 
+	/// Axis aligned box centered on the origin, `half_extents` from the center to the faces.
+	pub fn cuboid(half_extents: Vector3) -> impl Fn(Vector3) -> f64 {
+		move |p| {
+			let q = p.map(f64::abs) - half_extents;
+			q.map(|v| v.max(0.0)).magnitude() + q.max_component().min(0.0)
+		}
+	}
+
+	/// Distance from `p` to the line segment from `a` to `b`.
+	fn segment_distance(p: Vector3, a: Vector3, b: Vector3) -> f64 {
+		let (pa, ba) = (p - a, b - a);
+		let h = if ba.length_squared() > 0.0 { (pa.dot(ba) / ba.length_squared()).clamp(0.0, 1.0) } else { 0.0 };
+		(pa - ba * h).magnitude()
+	}
+
+	/// A cylinder with rounded ends around the segment from `a` to `b`, a sphere around `a`
+	/// if both ends coincide.
+	pub fn capsule(a: Vector3, b: Vector3, radius: f64) -> impl Fn(Vector3) -> f64 {
+		move |p| {
+			segment_distance(p, a, b) - radius
+		}
+	}
+
+	/// A tube of `radius` along a polyline, joints are rounded. A single point is a sphere,
+	/// no points is an empty scene.
+	pub fn polyline_tube(points: &[Vector3], radius: f64) -> impl Fn(Vector3) -> f64 {
+		let points = points.to_vec();
+		move |p| {
+			match points.len() {
+				0 => NO_SURFACE,
+				1 => (p - points[0]).magnitude() - radius,
+				_ => points.windows(2)
+					.map(|segment| segment_distance(p, segment[0], segment[1]))
+					.fold(NO_SURFACE, f64::min) - radius,
+			}
+		}
+	}
+
+	/// Infinite plane through `normal * -height`, `normal` has to be unit length. Positive on
+	/// the side `normal` points to.
+	pub fn plane(normal: Vector3, height: f64) -> impl Fn(Vector3) -> f64 {
+		move |p| {
+			p.dot(normal) + height
+		}
+	}
+
+	/// A finite, infinitely thin floor on the xz-plane at `height`. `half_extents` gives its
+	/// size along x (`half_extents.x`) and z (`half_extents.y`).
+	pub fn finite_plane(half_extents: Vector2, height: f64) -> impl Fn(Vector3) -> f64 {
+		move |p| {
+			Vector3::new(
+				(p.x.abs() - half_extents.x).max(0.0),
+				p.y - height,
+				(p.z.abs() - half_extents.y).max(0.0),
+			).magnitude()
+		}
+	}
+
+	/// Signed distance from `p` to a closed polygon, negative inside.
+	fn polygon_distance(contour: &[Vector2], p: Vector2) -> f64 {
+		let mut distance = f64::MAX;
+		let mut sign = 1.0;
+
+		for i in 0..contour.len() {
+			let (a, b) = (contour[i], contour[(i + contour.len() - 1) % contour.len()]);
+			let (ex, ey) = (b.x - a.x, b.y - a.y);
+			let (wx, wy) = (p.x - a.x, p.y - a.y);
+
+			let edge_length = ex * ex + ey * ey;
+			let t = if edge_length > 0.0 { ((wx * ex + wy * ey) / edge_length).clamp(0.0, 1.0) } else { 0.0 };
+			let (dx, dy) = (wx - ex * t, wy - ey * t);
+			distance = distance.min(dx * dx + dy * dy);
+
+			// winding test, flips the sign every time a ray towards +x crosses an edge
+			let c = (p.y >= a.y, p.y < b.y, ex * wy > ey * wx);
+			if (c.0 && c.1 && c.2) || (!c.0 && !c.1 && !c.2) {
+				sign = -sign;
+			}
+		}
+
+		sign * distance.sqrt()
+	}
+
+	/// Extrudes the closed 2D polygon `contour` (in the xy-plane) along z, `depth` thick and
+	/// centered on z = 0. Feed it glyph outlines to put labels in a scene.
+	pub fn extrude(contour: &[Vector2], depth: f64) -> impl Fn(Vector3) -> f64 {
+		let contour = contour.to_vec();
+
+		move |p| {
+			if contour.is_empty() {
+				return NO_SURFACE;
+			}
+
+			let wx = polygon_distance(&contour, Vector2::new(p.x, p.y));
+			let wy = p.z.abs() - depth / 2.0;
+
+			wx.max(wy).min(0.0) + (wx.max(0.0).powi(2) + wy.max(0.0).powi(2)).sqrt()
+		}
+	}
+
 	pub fn translate(sdf: impl Fn(Vector3) -> f64, translation: Vector3) -> impl Fn(Vector3) -> f64 {
 		move |p| {
 			sdf(p - translation)
 		}
 	}
 
+	/// Scales `sdf` uniformly by `factor` around the origin. Only uniform scaling is offered on
+	/// purpose: stretching one axis more than another no longer gives a distance that marching
+	/// can rely on, the field could overshoot the surface.
+	pub fn scale(sdf: impl Fn(Vector3) -> f64, factor: f64) -> impl Fn(Vector3) -> f64 {
+		move |p| {
+			sdf(p / factor) * factor
+		}
+	}
+
+	/// Hollows `sdf` out into a shell `2 * thickness` thick, centered on the original surface.
+	pub fn onion(sdf: impl Fn(Vector3) -> f64, thickness: f64) -> impl Fn(Vector3) -> f64 {
+		move |p| {
+			sdf(p).abs() - thickness
+		}
+	}
+
+	/// Repeats `sdf` endlessly, one copy every `period` along each axis, centered on the
+	/// origin's cell. Axes with a period of zero (or less) are not repeated.
+	pub fn repeat(sdf: impl Fn(Vector3) -> f64, period: Vector3) -> impl Fn(Vector3) -> f64 {
+		let fold = |c: f64, period: f64| {
+			if period > 0.0 {
+				(c + 0.5 * period).rem_euclid(period) - 0.5 * period
+			} else {
+				c
+			}
+		};
+
+		move |p| {
+			sdf(Vector3::new(fold(p.x, period.x), fold(p.y, period.y), fold(p.z, period.z)))
+		}
+	}
+
+	/// Twists `sdf` around the y-axis, the xz-plane being rotated by `k * p.y` radians at each
+	/// height. The result is no longer an exact distance and overestimates it for larger `k`,
+	/// so march it with a `step_scale` below 1.0 or rays may overshoot the surface.
+	pub fn twist(sdf: impl Fn(Vector3) -> f64, k: f64) -> impl Fn(Vector3) -> f64 {
+		move |p| {
+			let (sin, cos) = (k * p.y).sin_cos();
+			sdf(Vector3::new(cos * p.x - sin * p.z, p.y, sin * p.x + cos * p.z))
+		}
+	}
+
+	/// Rotates `sdf` by `angle` radians around `axis` (through the origin, need not be unit
+	/// length). The sample point is rotated back before evaluating.
+	pub fn rotate(sdf: impl Fn(Vector3) -> f64, axis: Vector3, angle: f64) -> impl Fn(Vector3) -> f64 {
+		move |p| sdf(p.rotate_around_axis(axis, -angle))
+	}
+
+	/// Folds the angle around the y-axis into `segments` mirrored wedges, giving `sdf` radial
+	/// symmetry. The result is only an approximate distance close to the fold seams, where the
+	/// nearest surface may lie in a neighbouring wedge.
+	pub fn kaleidoscope(sdf: impl Fn(Vector3) -> f64, segments: usize) -> impl Fn(Vector3) -> f64 {
+		let wedge = 2.0 * PI / segments.max(1) as f64;
+
+		move |p| {
+			let radius = (p.x * p.x + p.z * p.z).sqrt();
+			let mut angle = p.z.atan2(p.x).rem_euclid(wedge);
+			if angle > wedge / 2.0 {
+				angle = wedge - angle;
+			}
+
+			sdf(Vector3::new(radius * angle.cos(), p.y, radius * angle.sin()))
+		}
+	}
+
 	pub fn max(s1: impl Fn(Vector3) -> f64, s2: impl Fn(Vector3) -> f64) -> impl Fn(Vector3) -> f64 {
 		move |p| {
 			s1(p).max(s2(p))
@@ -158,6 +547,40 @@ pub mod sdf {
 		}
 	}
 
+	/// How deep boxed combinators may nest before evaluation gives up, see `union_all`.
+	pub const MAX_SDF_DEPTH: usize = 256;
+
+	thread_local! {
+		static SDF_DEPTH: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+	}
+
+	static DEPTH_WARNED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+	/// Union of any number of shapes, for scenes too big to nest `min` calls by hand.
+	/// Nesting deeper than `MAX_SDF_DEPTH` (e.g. a cyclic or malformed scene tree) evaluates to
+	/// `NO_SURFACE` instead of overflowing the stack.
+	pub fn union_all(sdfs: Vec<Box<dyn Fn(Vector3) -> f64>>) -> impl Fn(Vector3) -> f64 {
+		move |p| {
+			depth_guarded(|| sdfs.iter().map(|sdf| sdf(p)).fold(NO_SURFACE, f64::min))
+		}
+	}
+
+	/// Runs one level of a boxed combinator, or returns `NO_SURFACE` past `MAX_SDF_DEPTH`.
+	fn depth_guarded(evaluate: impl FnOnce() -> f64) -> f64 {
+		let depth = SDF_DEPTH.with(|d| d.get());
+		if depth >= MAX_SDF_DEPTH {
+			if !DEPTH_WARNED.swap(true, std::sync::atomic::Ordering::Relaxed) {
+				eprintln!("sdf nested deeper than {}, treating it as empty", MAX_SDF_DEPTH);
+			}
+			return NO_SURFACE;
+		}
+
+		SDF_DEPTH.with(|d| d.set(depth + 1));
+		let dist = evaluate();
+		SDF_DEPTH.with(|d| d.set(depth));
+		dist
+	}
+
 	fn mix(a: f64, b: f64, m: f64) -> f64 {
 		a + ((b - a) * m)
 	}
@@ -165,55 +588,320 @@ pub mod sdf {
 	pub fn smooth_min(s1: impl Fn(Vector3) -> f64, s2: impl Fn(Vector3) -> f64, k: f64) -> impl Fn(Vector3) -> f64 {
 		move |p| {
 			let (a, b) = (s1(p), s2(p));
-			let h = (0.5+0.5*(b-a)/k).min(1.0).max(0.0);
-			return mix(b, a, h) - k*h*(1.0-h);
+			let h = (0.5+0.5*(b-a)/k).clamp(0.0, 1.0);
+			mix(b, a, h) - k*h*(1.0-h)
 		}
 	}
 
+	/// Smooth intersection, the counterpart to `smooth_min`. Far from the seam it is exactly `max`.
+	pub fn smooth_max(s1: impl Fn(Vector3) -> f64, s2: impl Fn(Vector3) -> f64, k: f64) -> impl Fn(Vector3) -> f64 {
+		move |p| {
+			let (a, b) = (s1(p), s2(p));
+			let h = (0.5-0.5*(b-a)/k).clamp(0.0, 1.0);
+			mix(b, a, h) + k*h*(1.0-h)
+		}
+	}
+
+	/// Smooth intersection that also returns the blend factor, 1.0 where `s1` alone
+	/// defines the surface and 0.0 where `s2` does. Useful to blend materials across the rim.
+	pub fn smooth_max_factor(s1: impl Fn(Vector3) -> f64, s2: impl Fn(Vector3) -> f64, k: f64) -> impl Fn(Vector3) -> (f64, f64) {
+		move |p| {
+			let (a, b) = (s1(p), s2(p));
+			let h = (0.5-0.5*(b-a)/k).clamp(0.0, 1.0);
+			(mix(b, a, h) + k*h*(1.0-h), h)
+		}
+	}
+
+	/// Softmax style weights for blending three materials where three smoothly unioned
+	/// surfaces meet. `distances` are the three SDF values at the shading point, `k` plays
+	/// the same role as in `smooth_min`. The weights sum to 1, the nearest surface wins most.
+	pub fn blend_weights3(distances: [f64; 3], k: f64) -> [f64; 3] {
+		// shifting by the nearest distance keeps exp() from underflowing far from all surfaces
+		let nearest = distances[0].min(distances[1]).min(distances[2]);
+		let w = distances.map(|d| (-(d - nearest) / k).exp());
+		let sum = w[0] + w[1] + w[2];
+
+		w.map(|w| w / sum)
+	}
+
 	pub fn difference(s1: impl Fn(Vector3) -> f64, s2: impl Fn(Vector3) -> f64) -> impl Fn(Vector3) -> f64 {
 		move |p| {
 			s1(p).max(-s2(p))
 		}
 	}
+
+	/// `difference` with the carved edges rounded off over roughly `k`.
+	pub fn smooth_difference(s1: impl Fn(Vector3) -> f64, s2: impl Fn(Vector3) -> f64, k: f64) -> impl Fn(Vector3) -> f64 {
+		let carved = smooth_min(move |p| -s1(p), s2, k);
+		move |p| {
+			-carved(p)
+		}
+	}
+
+	/// Union of two shapes joined by `count` terraced steps of `step_size`, like stairs carved
+	/// into the corner between them (the "stairs" union from hg_sdf). The result is only an
+	/// approximate distance inside the stepped region.
+	pub fn stairs(s1: impl Fn(Vector3) -> f64, s2: impl Fn(Vector3) -> f64, step_size: f64, count: usize) -> impl Fn(Vector3) -> f64 {
+		let radius = step_size * count as f64;
+
+		move |p| {
+			let (a, b) = (s1(p), s2(p));
+			let u = b - radius;
+			let steps = 0.5 * (u + a + ((u - a + step_size).rem_euclid(2.0 * step_size) - step_size).abs());
+			a.min(b).min(steps)
+		}
+	}
+
+	/// Attaches a material to a shape, for scenes that are too simple to need more than
+	/// combining a few shaded shapes with `min_material` and `smooth_min_material`.
+	pub fn with_material(sdf: impl Fn(Vector3) -> f64, material: Material) -> impl Fn(Vector3) -> (f64, Material) {
+		move |p| {
+			(sdf(p), material)
+		}
+	}
+
+	/// `min` for shapes carrying a material, the nearer shape's material wins.
+	pub fn min_material(s1: impl Fn(Vector3) -> (f64, Material), s2: impl Fn(Vector3) -> (f64, Material)) -> impl Fn(Vector3) -> (f64, Material) {
+		move |p| {
+			let (a, b) = (s1(p), s2(p));
+			if a.0 <= b.0 { a } else { b }
+		}
+	}
+
+	/// `smooth_min` for shapes carrying a material, the materials blend across the seam.
+	pub fn smooth_min_material(s1: impl Fn(Vector3) -> (f64, Material), s2: impl Fn(Vector3) -> (f64, Material), k: f64) -> impl Fn(Vector3) -> (f64, Material) {
+		move |p| {
+			let ((a, ma), (b, mb)) = (s1(p), s2(p));
+			let h = (0.5+0.5*(b-a)/k).clamp(0.0, 1.0);
+			(mix(b, a, h) - k*h*(1.0-h), mb.mix(&ma, h))
+		}
+	}
+
+	/// Attaches a material id to a shape, so the shading can tell surfaces apart while the
+	/// geometry is combined with `min_id`, `max_id` and `smooth_min_id`.
+	pub fn with_id(sdf: impl Fn(Vector3) -> f64, id: u32) -> impl Fn(Vector3) -> (f64, u32) {
+		move |p| {
+			(sdf(p), id)
+		}
+	}
+
+	/// `min` for shapes carrying a material id, the nearer shape's id wins.
+	pub fn min_id(s1: impl Fn(Vector3) -> (f64, u32), s2: impl Fn(Vector3) -> (f64, u32)) -> impl Fn(Vector3) -> (f64, u32) {
+		move |p| {
+			let (a, b) = (s1(p), s2(p));
+			if a.0 <= b.0 { a } else { b }
+		}
+	}
+
+	/// `max` for shapes carrying a material id, the id of the shape defining the surface wins.
+	pub fn max_id(s1: impl Fn(Vector3) -> (f64, u32), s2: impl Fn(Vector3) -> (f64, u32)) -> impl Fn(Vector3) -> (f64, u32) {
+		move |p| {
+			let (a, b) = (s1(p), s2(p));
+			if a.0 >= b.0 { a } else { b }
+		}
+	}
+
+	/// `smooth_min` for shapes carrying a material id. Ids can't be blended, so the seam
+	/// switches to the other id halfway through the blend.
+	pub fn smooth_min_id(s1: impl Fn(Vector3) -> (f64, u32), s2: impl Fn(Vector3) -> (f64, u32), k: f64) -> impl Fn(Vector3) -> (f64, u32) {
+		move |p| {
+			let ((a, id_a), (b, id_b)) = (s1(p), s2(p));
+			let h = (0.5+0.5*(b-a)/k).clamp(0.0, 1.0);
+			(mix(b, a, h) - k*h*(1.0-h), if h >= 0.5 { id_a } else { id_b })
+		}
+	}
+
+	/// A signed distance field as a value, for scenes that are built at runtime and stored, e.g.
+	/// as `Vec<Box<dyn Sdf>>`. Every `Fn(Vector3) -> f64` is an `Sdf`, so the closure based
+	/// functions in this module keep working wherever an `Sdf` is expected.
+	pub trait Sdf {
+		fn distance(&self, p: Vector3) -> f64;
+	}
+
+	impl<F: Fn(Vector3) -> f64> Sdf for F {
+		fn distance(&self, p: Vector3) -> f64 {
+			self(p)
+		}
+	}
+
+	#[derive(Clone, Copy, Debug)]
+	pub struct Sphere {
+		pub radius: f64,
+	}
+
+	impl Sdf for Sphere {
+		fn distance(&self, p: Vector3) -> f64 {
+			p.magnitude() - self.radius
+		}
+	}
+
+	#[derive(Clone, Copy, Debug)]
+	pub struct Cuboid {
+		pub half_extents: Vector3,
+	}
+
+	impl Sdf for Cuboid {
+		fn distance(&self, p: Vector3) -> f64 {
+			cuboid(self.half_extents)(p)
+		}
+	}
+
+	#[derive(Clone, Copy, Debug)]
+	pub struct Plane {
+		pub normal: Vector3,
+		pub height: f64,
+	}
+
+	impl Sdf for Plane {
+		fn distance(&self, p: Vector3) -> f64 {
+			p.dot(self.normal) + self.height
+		}
+	}
+
+	#[derive(Clone, Copy, Debug)]
+	pub struct Translate<T> {
+		pub sdf: T,
+		pub translation: Vector3,
+	}
+
+	impl<T: Sdf> Sdf for Translate<T> {
+		fn distance(&self, p: Vector3) -> f64 {
+			self.sdf.distance(p - self.translation)
+		}
+	}
+
+	#[derive(Clone, Copy, Debug)]
+	pub struct Union<A, B>(pub A, pub B);
+
+	impl<A: Sdf, B: Sdf> Sdf for Union<A, B> {
+		fn distance(&self, p: Vector3) -> f64 {
+			self.0.distance(p).min(self.1.distance(p))
+		}
+	}
+
+	#[derive(Clone, Copy, Debug)]
+	pub struct Intersection<A, B>(pub A, pub B);
+
+	impl<A: Sdf, B: Sdf> Sdf for Intersection<A, B> {
+		fn distance(&self, p: Vector3) -> f64 {
+			self.0.distance(p).max(self.1.distance(p))
+		}
+	}
+
+	/// `A` with `B` cut out of it.
+	#[derive(Clone, Copy, Debug)]
+	pub struct Difference<A, B>(pub A, pub B);
+
+	impl<A: Sdf, B: Sdf> Sdf for Difference<A, B> {
+		fn distance(&self, p: Vector3) -> f64 {
+			self.0.distance(p).max(-self.1.distance(p))
+		}
+	}
+
+	#[derive(Clone, Copy, Debug)]
+	pub struct SmoothUnion<A, B> {
+		pub a: A,
+		pub b: B,
+		pub k: f64,
+	}
+
+	impl<A: Sdf, B: Sdf> Sdf for SmoothUnion<A, B> {
+		fn distance(&self, p: Vector3) -> f64 {
+			let (a, b) = (self.a.distance(p), self.b.distance(p));
+			let h = (0.5+0.5*(b-a)/self.k).clamp(0.0, 1.0);
+			mix(b, a, h) - self.k*h*(1.0-h)
+		}
+	}
+
+	/// `union_all` for boxed `Sdf`s, with the same guard against runaway nesting.
+	#[derive(Default)]
+	pub struct UnionAll(pub Vec<Box<dyn Sdf>>);
+
+	impl Sdf for UnionAll {
+		fn distance(&self, p: Vector3) -> f64 {
+			depth_guarded(|| self.0.iter().map(|sdf| sdf.distance(p)).fold(NO_SURFACE, f64::min))
+		}
+	}
 }
 
-use minifb::{Key, Window, WindowOptions};
+pub mod render;
+use render::Shading;
+pub mod post;
+pub mod output;
+pub mod noise;
+pub mod surface;
+pub mod color;
+pub mod scene;
 
-fn scene(pos: Vector3) -> f64 {
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+
+/// Smallest smooth-min blend the interactive controls allow. `smooth_min` divides by `k`.
+const MIN_BLEND: f64 = 0.05;
+const MAX_BLEND: f64 = 4.0;
+
+fn adjust_blend(k: f64, delta: f64) -> f64 {
+	(k + delta).clamp(MIN_BLEND, MAX_BLEND)
+}
+
+/// How much the edge of the cavity carved into the scene is rounded.
+const CARVE_BLEND: f64 = 0.25;
+
+fn scene(pos: Vector3, k: f64) -> f64 {
 	let sdf = {
-		sdf::difference(
+		sdf::smooth_difference(
 		sdf::smooth_min(
 			sdf::sphere(3.0),
 			sdf::translate(
 				sdf::sphere(2.0),
 				Vector3::new(0.0, 3.5, 0.0),
 			),
-			1.0
+			k
 		),
 		sdf::translate(
 			sdf::sphere(2.5),
 			Vector3::new(1.5, 1.5, -1.75),
-		))
+		),
+		CARVE_BLEND)
 	};
+	let sdf = sdf::min(sdf, sdf::plane(Vector3::new(0.0, 1.0, 0.0), 3.0));
 
 	sdf(pos)
 }
 
 
-fn estimate_normal(pos: Vector3) -> Vector3 {
-	Vector3::new(
-		scene(pos + Vector3::new(EPSILON, 0.0, 0.0)) - scene(pos - Vector3::new(EPSILON, 0.0, 0.0)),
-		scene(pos + Vector3::new(0.0, EPSILON, 0.0)) - scene(pos - Vector3::new(0.0, EPSILON, 0.0)),
-		scene(pos + Vector3::new(0.0, 0.0, EPSILON)) - scene(pos - Vector3::new(0.0, 0.0, EPSILON))
-	).normalize()
+#[cfg(feature = "serde")]
+const CAMERA_FILE: &str = "camera.json";
+/// Where F6 writes the march trace of the center pixel.
+const TRACE_FILE: &str = "march_trace.csv";
+
+/// Camera speed in units per second.
+const MOVE_SPEED: f64 = 4.0;
+/// Camera turn rate in radians per second.
+const TURN_SPEED: f64 = 1.5;
+
+/// Radians the camera orbits per frame.
+const ORBIT_STEP: f64 = 0.05;
+
+/// Moves `camera` by `angle` around the y-axis through the origin, still looking at the origin.
+fn orbit(camera: &Camera, angle: f64) -> Camera {
+	let position = camera.position.rotate_around_axis(Vector3::new(0.0, 1.0, 0.0), -angle);
+
+	Camera::look_at(position, Vector3::new(0.0, 0.0, 0.0), camera.fov)
 }
 
 fn main() {
 	const WIDTH: usize = 600;
 	const HEIGHT: usize = 600;
-	const MAX_STEPS: usize = 50;
+
+	// `--png <path>` writes a single frame to a file instead of opening a window
+	#[cfg(feature = "image")]
+	if let Some(path) = std::env::args().skip_while(|arg| arg != "--png").nth(1) {
+		output::render_to_image(WIDTH, HEIGHT, path, false).expect("writing the image failed");
+		return;
+	}
 
 	let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
+	let mut presented = buffer.clone();
 
 	let mut window = Window::new(
 		"Test - ESC to exit",
@@ -226,51 +914,549 @@ fn main() {
 	});
 
 	let mut time = ::std::time::Instant::now();
+	let mut blend = 1.0;
+	#[cfg(feature = "serde")]
+	let mut camera = Camera::load(CAMERA_FILE).unwrap_or_default();
+	#[cfg(not(feature = "serde"))]
+	let mut camera = Camera::default();
+	let mut orbiting = false;
+	let mut params = render::RenderParams {
+		shadows: true,
+		light: render::Light { radius: 0.5, ..render::Light::default() },
+		..render::RenderParams::default()
+	};
+	let mut resolution = render::AdaptiveResolution::default();
 
 	while window.is_open() && !window.is_key_down(Key::Escape) {
 		if time.elapsed().as_millis() < 200 {
 			continue;
 		}
+		let dt = time.elapsed().as_secs_f64();
 		time = ::std::time::Instant::now();
 
-		for y in 0..HEIGHT {
-			for x in 0..WIDTH {
-				buffer[x + y * WIDTH] = (|| {
-					let mut depth = EPSILON;
-					let ray = generate_primary_ray((WIDTH, HEIGHT), (x, y), 64.0);
+		// WASD moves along the camera's axes, the arrow keys look around
+		let key_axis = |negative, positive| {
+			window.is_key_down(positive) as i32 as f64 - window.is_key_down(negative) as i32 as f64
+		};
+		let movement = camera.forward * key_axis(Key::S, Key::W) + camera.right() * key_axis(Key::A, Key::D);
+		camera.position += movement * (MOVE_SPEED * dt);
+		let (yaw, pitch) = (key_axis(Key::Left, Key::Right), key_axis(Key::Down, Key::Up));
+		if yaw != 0.0 || pitch != 0.0 {
+			camera = camera.turn(yaw * TURN_SPEED * dt, pitch * TURN_SPEED * dt);
+		}
+
+		// , and . tune the smooth-min blend between the two spheres
+		if window.is_key_down(Key::Comma) {
+			blend = adjust_blend(blend, -0.1);
+		}
+		if window.is_key_down(Key::Period) {
+			blend = adjust_blend(blend, 0.1);
+		}
+		// O starts and stops orbiting the camera around the scene
+		if window.is_key_pressed(Key::O, KeyRepeat::No) {
+			orbiting = !orbiting;
+		}
+		if orbiting {
+			camera = orbit(&camera, ORBIT_STEP);
+		}
+		// H switches the soft shadows off and on again
+		if window.is_key_pressed(Key::H, KeyRepeat::No) {
+			params.shadows = !params.shadows;
+		}
+		// N switches between the normals debug view and Lambert shading
+		if window.is_key_pressed(Key::N, KeyRepeat::No) {
+			params.shading = match params.shading {
+				Shading::Normals => Shading::Lambert,
+				_ => Shading::Normals,
+			};
+		}
+
+		#[cfg(feature = "serde")]
+		{
+			if window.is_key_pressed(Key::F5, KeyRepeat::No) {
+				if let Err(e) = camera.save(CAMERA_FILE) {
+					println!("Failed to save the camera: {}", e);
+				}
+			}
+		}
+
+		if window.is_key_pressed(Key::F6, KeyRepeat::No) {
+			let steps = render::pixel_march_trace(&camera, (WIDTH, HEIGHT), (WIDTH / 2, HEIGHT / 2), &|p| scene(p, blend), &params);
+			if let Err(e) = output::write_march_trace(&steps, TRACE_FILE) {
+				println!("Failed to write the march trace: {}", e);
+			}
+		}
+
+		let divisor = resolution.update(&camera);
+		render::render_scaled_into(&mut buffer, WIDTH, HEIGHT, divisor, &camera, &|p| scene(p, blend), &params);
+
+		// minifb can only push whole frames, so the dirty rect just tells us whether to push at all
+		if output::dirty_rect(&presented, &buffer, WIDTH).is_some() {
+			// We unwrap here as we want this code to exit if it fails. Real applications may want to handle this in a different way
+			window.update_with_buffer(&buffer).unwrap();
+			presented.copy_from_slice(&buffer);
+		} else {
+			window.update();
+		}
+	}
+
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn secondary_rays_start_outside_the_surface() {
+		let sphere = sdf::sphere(1.0);
+		let ray = Ray::new(Vector3::new(0.0, 0.0, -5.0), Vector3::new(0.0, 0.0, 1.0));
+		let hit = match render::march(&ray, &sphere, 1.0) {
+			render::March::Hit(hit) => hit,
+			render::March::Miss { .. } => panic!("the ray has to hit the sphere"),
+		};
+
+		let towards_light = Vector3::new(0.3, 0.5, -1.0).normalize();
+		let shadow_ray = Ray::from_surface(hit.position, hit.normal, towards_light, SURFACE_BIAS);
+
+		assert!(sphere(shadow_ray.origin) > 0.0);
+		assert!(sphere(shadow_ray.origin) > sphere(hit.position));
+	}
+
+	#[test]
+	fn finite_plane_distances() {
+		let floor = sdf::finite_plane(Vector2::new(2.0, 1.0), -1.0);
+
+		// beyond the x extent, level with the floor: the distance to that edge
+		assert!((floor(Vector3::new(3.0, -1.0, 0.0)) - 1.0).abs() < 1e-9);
+		// beyond a corner
+		assert!((floor(Vector3::new(5.0, -1.0, 5.0)) - 5.0).abs() < 1e-9);
+		// above the center: the height difference
+		assert!((floor(Vector3::new(0.0, 1.5, 0.0)) - 2.5).abs() < 1e-9);
+	}
+
+	#[test]
+	fn blend_stays_above_the_minimum() {
+		assert_eq!(adjust_blend(0.1, -1.0), MIN_BLEND);
+		assert_eq!(adjust_blend(MIN_BLEND, -0.1), MIN_BLEND);
+		assert!(adjust_blend(1.0, -0.1) > MIN_BLEND);
+		assert_eq!(adjust_blend(MAX_BLEND, 0.1), MAX_BLEND);
+	}
+
+	#[test]
+	fn kaleidoscope_is_rotationally_symmetric() {
+		let segments = 6;
+		let field = sdf::kaleidoscope(sdf::translate(sdf::sphere(0.4), Vector3::new(1.5, 0.3, 0.2)), segments);
+		let wedge = 2.0 * PI / segments as f64;
+		let up = Vector3::new(0.0, 1.0, 0.0);
+
+		for &p in &[Vector3::new(1.2, 0.1, 0.5), Vector3::new(-0.7, 0.4, 2.0), Vector3::new(0.3, -1.0, -1.1)] {
+			for k in 1..segments {
+				let rotated = p.rotate_around_axis(up, wedge * k as f64);
+				assert!((field(p) - field(rotated)).abs() < 1e-9);
+			}
+		}
+	}
+
+	/// A few points around the two overlapping unit spheres used by the operator tests.
+	fn sample_points() -> Vec<Vector3> {
+		let mut points = Vec::new();
+		for x in -3..=3 {
+			for y in -2..=2 {
+				points.push(Vector3::new(x as f64 * 0.5, y as f64 * 0.5, 0.25));
+			}
+		}
+		points
+	}
+
+	fn left() -> impl Fn(Vector3) -> f64 {
+		sdf::translate(sdf::sphere(1.0), Vector3::new(-0.5, 0.0, 0.0))
+	}
+
+	fn right() -> impl Fn(Vector3) -> f64 {
+		sdf::translate(sdf::sphere(1.0), Vector3::new(0.5, 0.0, 0.0))
+	}
+
+	#[test]
+	fn smooth_max_factor_approaches_max() {
+		let sharp = sdf::smooth_max_factor(left(), right(), 1e-6);
+		let smooth = sdf::smooth_max_factor(left(), right(), 0.5);
+
+		for p in sample_points() {
+			let (distance, _) = sharp(p);
+			assert!((distance - left()(p).max(right()(p))).abs() < 1e-5);
 
-					for _ in 0..MAX_STEPS {
-						let frag_pos = ray.origin + ray.direction * depth;
-						let dist = scene(frag_pos);
+			let (_, factor) = smooth(p);
+			assert!((0.0..=1.0).contains(&factor));
+		}
+	}
+
+	#[test]
+	fn blend_weights_sum_to_one_and_favor_the_nearest() {
+		let weights = sdf::blend_weights3([0.4, 0.1, 0.7], 0.3);
+
+		assert!((weights.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+		assert!(weights[1] > weights[0] && weights[1] > weights[2]);
+
+		// far from all three surfaces the weights still have to be finite
+		let far = sdf::blend_weights3([1.0e4, 1.0e4 + 0.1, 1.0e4 + 0.2], 0.01);
+		assert!((far.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+	}
+
+	#[test]
+	fn union_all_matches_nested_min() {
+		let a = || sdf::translate(sdf::sphere(1.0), Vector3::new(-1.5, 0.0, 0.0));
+		let b = || sdf::sphere(0.5);
+		let c = || sdf::translate(sdf::sphere(0.75), Vector3::new(1.0, 1.0, 0.5));
+
+		let union = sdf::union_all(vec![Box::new(a()), Box::new(b()), Box::new(c())]);
+		let nested = sdf::min(sdf::min(a(), b()), c());
+
+		for p in sample_points() {
+			assert_eq!(union(p), nested(p));
+		}
+		assert_eq!(sdf::union_all(vec![])(Vector3::new(0.0, 0.0, 0.0)), sdf::NO_SURFACE);
+	}
+
+	fn same(a: Vector3, b: Vector3) -> bool {
+		(a - b).magnitude() < 1e-9
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn camera_round_trips_through_a_file() {
+		let camera = Camera::look_at(Vector3::new(1.0, 2.0, -3.0), Vector3::new(0.0, 0.5, 0.0), 48.0);
+		let path = std::env::temp_dir().join(format!("raymarcher-camera-{}.json", std::process::id()));
+
+		camera.save(&path).unwrap();
+		let loaded = Camera::load(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+
+		assert!(same(loaded.position, camera.position));
+		assert!(same(loaded.forward, camera.forward));
+		assert!(same(loaded.up, camera.up));
+		assert_eq!(loaded.fov, camera.fov);
+	}
+
+	#[test]
+	fn extruded_square_matches_a_cuboid() {
+		let square = [Vector2::new(-0.5, -0.5), Vector2::new(0.5, -0.5), Vector2::new(0.5, 0.5), Vector2::new(-0.5, 0.5)];
+		let extruded = sdf::extrude(&square, 1.0);
+		let cuboid = sdf::cuboid(Vector3::new(0.5, 0.5, 0.5));
+
+		for p in sample_points().into_iter().chain([Vector3::new(0.1, -0.2, 0.0), Vector3::new(2.0, 1.0, -3.0)]) {
+			assert!((extruded(p) - cuboid(p)).abs() < 1e-9, "{:?}: {} vs {}", p, extruded(p), cuboid(p));
+		}
+	}
+
+	fn nested_unions(levels: usize) -> Box<dyn Fn(Vector3) -> f64> {
+		(0..levels).fold(Box::new(sdf::sphere(1.0)), |inner, _| Box::new(sdf::union_all(vec![inner])))
+	}
+
+	#[test]
+	fn unions_nested_too_deep_are_empty() {
+		let origin = Vector3::new(0.0, 0.0, 0.0);
+
+		assert!((nested_unions(sdf::MAX_SDF_DEPTH)(origin) + 1.0).abs() < 1e-9);
+		assert_eq!(nested_unions(sdf::MAX_SDF_DEPTH + 1)(origin), sdf::NO_SURFACE);
+	}
+
+	#[test]
+	fn cross_follows_the_right_hand_rule() {
+		let (x, y, z) = (Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 0.0, 1.0));
+		assert!(same(x.cross(y), z));
+		assert!(same(y.cross(x), -z));
+
+		let (a, b) = (Vector3::new(1.0, 2.0, -0.5), Vector3::new(-3.0, 0.25, 4.0));
+		let c = a.cross(b);
+		assert!(c.dot(a).abs() < 1e-9 && c.dot(b).abs() < 1e-9);
+	}
+
+	#[test]
+	fn min_material_keeps_the_nearer_material() {
+		let red = render::Material { albedo: Vector3::new(1.0, 0.0, 0.0), ..render::Material::default() };
+		let blue = render::Material { albedo: Vector3::new(0.0, 0.0, 1.0), ..render::Material::default() };
+		let (l, r) = (left(), right());
+		let scene = sdf::min_material(move |p| (l(p), red), move |p| (r(p), blue));
+
+		let (dist, material) = scene(Vector3::new(-2.0, 0.0, 0.0));
+		assert!((dist - 0.5).abs() < 1e-9);
+		assert!(same(material.albedo, red.albedo));
+
+		let (dist, material) = scene(Vector3::new(3.0, 0.0, 0.0));
+		assert!((dist - 1.5).abs() < 1e-9);
+		assert!(same(material.albedo, blue.albedo));
+	}
+
+	#[test]
+	fn vectors_multiply_component_wise() {
+		let tinted = Vector3::new(1.0, 0.5, 0.2) * Vector3::new(0.5, 2.0, 0.0);
+		assert!(same(tinted, Vector3::new(0.5, 1.0, 0.0)));
+	}
+
+	#[test]
+	fn scalars_scale_from_the_left() {
+		let v = Vector3::new(1.0, -2.0, 0.5);
+		assert!(same(2.0 * v, v * 2.0));
+		assert!(same(2.0 * v, Vector3::new(2.0, -4.0, 1.0)));
+	}
+
+	#[test]
+	fn negation_flips_every_component() {
+		let v = Vector3::new(1.0, -2.0, 0.5);
+		assert!(same(-v, Vector3::new(-1.0, 2.0, -0.5)));
+		assert!(same(-(-v), v));
+	}
+
+	#[test]
+	fn compound_assignment_matches_the_binary_operators() {
+		let (a, b) = (Vector3::new(1.0, 2.0, 3.0), Vector3::new(0.5, -1.0, 4.0));
+
+		let mut sum = a;
+		sum += b;
+		assert!((sum - (a + b)).magnitude() < 1e-9);
+
+		let mut difference = a;
+		difference -= b;
+		assert!((difference - (a - b)).magnitude() < 1e-9);
+	}
+
+	#[test]
+	fn reflect_mirrors_about_the_normal() {
+		let incoming = Vector3::new(1.0, -1.0, 0.0).normalize();
+		let up = Vector3::new(0.0, 1.0, 0.0);
+
+		assert!(same(incoming.reflect(up), Vector3::new(1.0, 1.0, 0.0).normalize()));
+		assert!(same(Vector3::new(0.0, -1.0, 0.0).reflect(up), up));
+	}
+
+	#[test]
+	fn length_squared_skips_the_square_root() {
+		for p in sample_points() {
+			assert!((p.length_squared() - p.magnitude().powi(2)).abs() < 1e-9);
+		}
+	}
+
+	#[test]
+	fn lerp_hits_both_ends_and_the_middle() {
+		let (a, b) = (Vector3::new(0.0, 2.0, -1.0), Vector3::new(4.0, 0.0, 1.0));
+
+		assert!(same(a.lerp(b, 0.0), a));
+		assert!(same(a.lerp(b, 0.5), Vector3::new(2.0, 1.0, 0.0)));
+		assert!(same(a.lerp(b, 1.0), b));
+	}
+
+	#[test]
+	fn cuboid_distance_inside_on_and_outside() {
+		let cuboid = sdf::cuboid(Vector3::new(1.0, 2.0, 3.0));
 
-						if dist < EPSILON {
-							// were inside the surface
-							let normal = estimate_normal(frag_pos);
+		assert!((cuboid(Vector3::new(0.0, 0.0, 0.0)) + 1.0).abs() < 1e-9);
+		assert!(cuboid(Vector3::new(1.0, 0.5, -1.0)).abs() < 1e-9);
+		assert!((cuboid(Vector3::new(0.0, 5.0, 0.0)) - 3.0).abs() < 1e-9);
+		// off a corner the distance is to the corner itself
+		assert!((cuboid(Vector3::new(2.0, 3.0, 3.0)) - 2f64.sqrt()).abs() < 1e-9);
+	}
+
+	#[test]
+	fn frustum_corners_are_symmetric_about_forward() {
+		let camera = Camera::default();
+		let [top_left, top_right, bottom_right, bottom_left] = camera.frustum_corners(16.0 / 9.0);
+
+		assert!(same(top_left, Vector3::new(-top_right.x, top_right.y, top_right.z)));
+		assert!(same(bottom_left, Vector3::new(top_left.x, -top_left.y, top_left.z)));
+		assert!(same(bottom_right, Vector3::new(top_right.x, -top_right.y, top_right.z)));
+
+		// the camera looks along +z, the top edge is half the field of view above it
+		assert!(top_left.x < 0.0 && top_left.y > 0.0);
+		assert!((top_left.y / top_left.z - (camera.fov / 2.0).to_radians().tan()).abs() < 1e-9);
+	}
+
+	#[test]
+	fn two_point_tube_is_a_capsule() {
+		let (a, b) = (Vector3::new(-1.0, 0.0, 0.0), Vector3::new(1.0, 0.5, 0.0));
+		let tube = sdf::polyline_tube(&[a, b], 0.25);
+		let capsule = sdf::capsule(a, b, 0.25);
+
+		for p in sample_points() {
+			assert!((tube(p) - capsule(p)).abs() < 1e-9);
+		}
+	}
 
-							let light_pos = Vector3::new(4.0, 3.0, -6.0);
-							let light_dir = (light_pos - frag_pos).normalize();
-							let light_strength = 10.0;
+	#[test]
+	fn tube_joints_are_rounded() {
+		// an L bent at the origin
+		let tube = sdf::polyline_tube(&[Vector3::new(0.0, 2.0, 0.0), Vector3::new(0.0, 0.0, 0.0), Vector3::new(2.0, 0.0, 0.0)], 0.5);
 
-							let distance = (light_pos - frag_pos).magnitude();
-							let attenuation = 1.0 / (distance*distance) * light_strength;
+		assert!((tube(Vector3::new(0.0, 0.0, 0.0)) + 0.5).abs() < 1e-9);
+		// outside the bend the nearest point is the joint itself
+		let outside = Vector3::new(-1.0, -1.0, 0.0);
+		assert!((tube(outside) - (2f64.sqrt() - 0.5)).abs() < 1e-9);
+		assert!((tube(Vector3::new(1.0, 1.0, 0.0)) - 0.5).abs() < 1e-9);
+	}
 
-							let cos_theta = light_dir.dot(normal).max(0.0);
+	#[test]
+	fn plane_is_positive_on_the_normal_side() {
+		let floor = sdf::plane(Vector3::new(0.0, 1.0, 0.0), 1.0);
 
-							let color = Vector3::new(1.0, 0.0, 0.0) * cos_theta * attenuation + Vector3::new(0.04, 0.04, 0.04);
-							return U8Color::from_vec(color, 255).as_u32()
-						}
+		assert!((floor(Vector3::new(3.0, 2.0, -4.0)) - 3.0).abs() < 1e-9);
+		assert!(floor(Vector3::new(0.0, -1.0, 0.0)).abs() < 1e-9);
+		assert!((floor(Vector3::new(0.0, -3.0, 0.0)) + 2.0).abs() < 1e-9);
+	}
 
-						depth += dist;
-					}
+	#[test]
+	fn capsule_distance_to_the_axis_and_the_caps() {
+		let (a, b) = (Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 1.0, 0.0));
+		let capsule = sdf::capsule(a, b, 0.5);
+
+		assert!((capsule(Vector3::new(0.0, 0.3, 0.0)) + 0.5).abs() < 1e-9);
+		assert!((capsule(Vector3::new(2.0, 0.3, 0.0)) - 1.5).abs() < 1e-9);
+		assert!((capsule(Vector3::new(0.0, 3.0, 0.0)) - 1.5).abs() < 1e-9);
+
+		let sphere = sdf::capsule(a, a, 0.5);
+		assert!((sphere(Vector3::new(2.0, -1.0, 0.0)) - 1.5).abs() < 1e-9);
+	}
+
+	#[test]
+	fn rotated_sphere_moves_around_the_axis() {
+		let sphere = sdf::translate(sdf::sphere(1.0), Vector3::new(2.0, 0.0, 0.0));
+		let rotated = sdf::rotate(sphere, Vector3::new(0.0, 0.0, 1.0), PI / 2.0);
+
+		assert!((rotated(Vector3::new(0.0, 2.0, 0.0)) + 1.0).abs() < 1e-9);
+		assert!((rotated(Vector3::new(2.0, 0.0, 0.0)) - (8f64.sqrt() - 1.0)).abs() < 1e-9);
+
+		// around y the sphere swings from +x to -z
+		let sphere = sdf::translate(sdf::sphere(1.0), Vector3::new(2.0, 0.0, 0.0));
+		let rotated = sdf::rotate(sphere, Vector3::new(0.0, 1.0, 0.0), PI / 2.0);
+
+		assert!((rotated(Vector3::new(0.0, 0.0, -2.0)) + 1.0).abs() < 1e-9);
+	}
+
+	#[test]
+	fn scaling_a_unit_sphere_by_two_doubles_its_radius() {
+		let scaled = sdf::scale(sdf::sphere(1.0), 2.0);
+		let sphere = sdf::sphere(2.0);
+
+		for p in sample_points().into_iter().chain([Vector3::new(0.0, 5.0, 0.0)]) {
+			assert!((scaled(p) - sphere(p)).abs() < 1e-9);
+		}
+	}
+
+	#[test]
+	fn smooth_max_is_max_far_from_the_seam() {
+		let (l, r) = (left(), right());
+		let smooth = sdf::smooth_max(left(), right(), 0.1);
+
+		// the spheres' distances differ by far more than k out here
+		for p in [Vector3::new(-3.0, 0.0, 0.0), Vector3::new(4.0, 0.5, 0.0)] {
+			assert!((smooth(p) - l(p).max(r(p))).abs() < 1e-9);
+		}
+	}
 
-					0x000000
-				})()
+	#[test]
+	fn smooth_difference_sharpens_into_difference() {
+		let sharp = sdf::difference(left(), right());
+		let smooth = sdf::smooth_difference(left(), right(), 1e-6);
+
+		for p in sample_points() {
+			assert!((smooth(p) - sharp(p)).abs() < 1e-6, "{:?}", p);
+		}
+	}
+
+	#[test]
+	fn boxed_sdfs_union_to_the_nearest() {
+		use sdf::Sdf;
+
+		let shapes: Vec<Box<dyn Sdf>> = vec![
+			Box::new(sdf::Sphere { radius: 1.0 }),
+			Box::new(sdf::Translate { sdf: sdf::Cuboid { half_extents: Vector3::new(0.5, 0.5, 0.5) }, translation: Vector3::new(3.0, 0.0, 0.0) }),
+			Box::new(left()),
+		];
+		let union = sdf::UnionAll(shapes);
+
+		assert!((union.distance(Vector3::new(3.0, 2.0, 0.0)) - 1.5).abs() < 1e-9);
+		assert!((union.distance(Vector3::new(-3.0, 0.0, 0.0)) - 1.5).abs() < 1e-9);
+		assert_eq!(sdf::UnionAll::default().distance(Vector3::new(0.0, 0.0, 0.0)), sdf::NO_SURFACE);
+	}
+
+	#[test]
+	fn min_id_keeps_the_nearer_id() {
+		let (l, r) = (left(), right());
+		let scene = sdf::min_id(move |p| (l(p), 1), move |p| (r(p), 2));
+
+		assert_eq!(scene(Vector3::new(-2.0, 0.0, 0.0)).1, 1);
+		assert_eq!(scene(Vector3::new(2.0, 0.0, 0.0)).1, 2);
+	}
+
+	#[test]
+	fn repetition_is_periodic() {
+		let period = Vector3::new(3.0, 0.0, 2.0);
+		let repeated = sdf::repeat(sdf::sphere(0.5), period);
+
+		for p in sample_points() {
+			assert!((repeated(p) - repeated(p + period)).abs() < 1e-9);
+			assert!((repeated(p) - repeated(p - period * 2.0)).abs() < 1e-9);
+		}
+		// y is not repeated
+		assert!((repeated(Vector3::new(0.0, 4.0, 0.0)) - 3.5).abs() < 1e-9);
+	}
+
+	#[test]
+	fn stairs_carve_the_requested_number_of_steps() {
+		let (step, count) = (0.25, 4);
+		let floor = |p: Vector3| p.y;
+		let wall = |p: Vector3| p.x;
+		let stairs = sdf::stairs(floor, wall, step, count);
+
+		// height of the surface above each x in the corner, by bisection
+		let height = |x: f64| {
+			let (mut below, mut above) = (0.0, 1.0);
+			for _ in 0..50 {
+				let y = (below + above) / 2.0;
+				if stairs(Vector3::new(x, y, 0.0)) < 0.0 { below = y } else { above = y }
 			}
+			below
+		};
+
+		let mut levels: Vec<i64> = (0..100).map(|i| (height((i as f64 + 0.5) / 100.0) / step).round() as i64).collect();
+		levels.dedup();
+		assert_eq!(levels, vec![3, 2, 1, 0]);
+	}
+
+	#[test]
+	fn zero_twist_leaves_the_shape_alone() {
+		let cuboid = sdf::cuboid(Vector3::new(1.0, 2.0, 0.5));
+		let twisted = sdf::twist(sdf::cuboid(Vector3::new(1.0, 2.0, 0.5)), 0.0);
+
+		for p in sample_points() {
+			assert!((twisted(p) - cuboid(p)).abs() < 1e-12);
 		}
+	}
+
+	#[test]
+	fn onion_hollows_a_sphere() {
+		let shell = sdf::onion(sdf::sphere(1.0), 0.1);
+
+		assert!((shell(Vector3::new(0.0, 0.0, 0.0)) - 0.9).abs() < 1e-9);
+		assert!((shell(Vector3::new(1.0, 0.0, 0.0)) + 0.1).abs() < 1e-9);
+		assert!(shell(Vector3::new(0.0, 1.1, 0.0)).abs() < 1e-9);
+		assert!(shell(Vector3::new(0.0, 0.0, 0.9)).abs() < 1e-9);
+	}
 
+	#[test]
+	fn center_pixel_looks_along_forward() {
+		let camera = Camera::look_at(Vector3::new(3.0, 1.0, -4.0), Vector3::new(0.0, 0.5, 2.0), 50.0);
+		let ray = generate_primary_ray(&camera, (7, 5), (3, 2));
 
-		// We unwrap here as we want this code to exit if it fails. Real applications may want to handle this in a different way
-		window.update_with_buffer(&buffer).unwrap();
+		assert!(same(ray.origin, camera.position));
+		assert!(same(ray.direction, camera.forward));
 	}
 
+	#[test]
+	fn rotating_x_a_quarter_turn_about_z_gives_y() {
+		let rotated = Vector3::new(1.0, 0.0, 0.0).rotate_around_axis(Vector3::new(0.0, 0.0, 1.0), PI / 2.0);
+		assert!(same(rotated, Vector3::new(0.0, 1.0, 0.0)));
+
+		// the axis needn't be unit length
+		let rotated = Vector3::new(1.0, 0.0, 0.0).rotate_around_axis(Vector3::new(0.0, 0.0, 3.0), PI / 2.0);
+		assert!(same(rotated, Vector3::new(0.0, 1.0, 0.0)));
+	}
 }
@@ -0,0 +1,121 @@
+use super::*;
+
+/// Deterministically maps a lattice point to a value in `[-1, 1]`.
+fn hash(x: i64, y: i64, z: i64) -> f64 {
+	let mut h = (x as u64).wrapping_mul(0x8da6_b343)
+		^ (y as u64).wrapping_mul(0xd816_3841)
+		^ (z as u64).wrapping_mul(0xcb1a_b31f);
+
+	// murmur3 finalizer, so neighbouring lattice points don't correlate
+	h ^= h >> 33;
+	h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+	h ^= h >> 33;
+	h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+	h ^= h >> 33;
+
+	(h >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+}
+
+/// Small deterministic random number generator (SplitMix64), the same seed always produces
+/// the same sequence.
+#[derive(Clone, Debug)]
+pub struct Rng {
+	state: u64,
+}
+
+impl Rng {
+	pub fn new(seed: u64) -> Self {
+		Rng { state: seed }
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		let mut z = self.state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		z ^ (z >> 31)
+	}
+
+	/// Uniform in `[0, 1)`.
+	pub fn next_f64(&mut self) -> f64 {
+		(self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+	}
+
+	/// Uniform in `[min, max)`.
+	pub fn range(&mut self, min: f64, max: f64) -> f64 {
+		min + (max - min) * self.next_f64()
+	}
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+	a + (b - a) * t
+}
+
+/// Value noise: random values on the integer lattice, smoothly interpolated in between.
+/// Continuous, in `[-1, 1]`, and the same for the same `p` on every run.
+pub fn noise3(p: Vector3) -> f64 {
+	let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+	let (x, y, z) = (fx as i64, fy as i64, fz as i64);
+
+	let fade = |t: f64| t * t * (3.0 - 2.0 * t);
+	let (u, v, w) = (fade(p.x - fx), fade(p.y - fy), fade(p.z - fz));
+
+	let face = |z| lerp(
+		lerp(hash(x, y, z), hash(x + 1, y, z), u),
+		lerp(hash(x, y + 1, z), hash(x + 1, y + 1, z), u),
+		v,
+	);
+
+	lerp(face(z), face(z + 1), w)
+}
+
+/// Fractal brownian motion, `octaves` layers of `noise3` each at twice the frequency and half
+/// the amplitude of the previous one. Normalized so it stays in `[-1, 1]`.
+pub fn fbm(p: Vector3, octaves: usize) -> f64 {
+	let mut sum = 0.0;
+	let mut total_amplitude = 0.0;
+	let mut amplitude = 1.0;
+	let mut frequency = 1.0;
+
+	for _ in 0..octaves {
+		sum += noise3(p * frequency) * amplitude;
+		total_amplitude += amplitude;
+		amplitude *= 0.5;
+		frequency *= 2.0;
+	}
+
+	if total_amplitude == 0.0 {
+		return 0.0;
+	}
+
+	sum / total_amplitude
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn points() -> impl Iterator<Item = Vector3> {
+		let mut rng = Rng::new(7);
+		(0..500).map(move |_| Vector3::new(rng.range(-20.0, 20.0), rng.range(-20.0, 20.0), rng.range(-20.0, 20.0)))
+	}
+
+	#[test]
+	fn noise_is_continuous() {
+		let step = Vector3::new(1e-4, -1e-4, 1e-4);
+
+		for p in points() {
+			assert!((noise3(p) - noise3(p + step)).abs() < 1e-2);
+			assert!((fbm(p, 5) - fbm(p + step, 5)).abs() < 1e-2);
+		}
+	}
+
+	#[test]
+	fn noise_stays_in_bounds() {
+		for p in points() {
+			assert!((-1.0..=1.0).contains(&noise3(p)));
+			assert!((-1.0..=1.0).contains(&fbm(p, 6)));
+		}
+		assert_eq!(noise3(Vector3::new(1.5, 2.5, 3.5)), noise3(Vector3::new(1.5, 2.5, 3.5)));
+	}
+}
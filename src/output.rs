@@ -0,0 +1,373 @@
+use super::*;
+use render::MarchStep;
+#[cfg(feature = "image")]
+use render::RenderParams;
+#[cfg(feature = "image")]
+use color::ColorSpace;
+#[cfg(feature = "serde")]
+use render::RenderStats;
+
+/// Bounding box of the pixels that changed between two frames, in pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DirtyRect {
+	pub x: usize,
+	pub y: usize,
+	pub width: usize,
+	pub height: usize,
+}
+
+impl DirtyRect {
+	/// Smallest rectangle containing all of `pixels`, `None` if there are none.
+	pub fn from_pixels(pixels: impl IntoIterator<Item = (usize, usize)>) -> Option<Self> {
+		let mut bounds: Option<(usize, usize, usize, usize)> = None;
+
+		for (x, y) in pixels {
+			bounds = Some(match bounds {
+				None => (x, y, x, y),
+				Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+			});
+		}
+
+		bounds.map(|(x0, y0, x1, y1)| DirtyRect {
+			x: x0,
+			y: y0,
+			width: x1 - x0 + 1,
+			height: y1 - y0 + 1,
+		})
+	}
+}
+
+/// Compares two frames of the same size and returns the region that differs.
+pub fn dirty_rect(previous: &[u32], current: &[u32], width: usize) -> Option<DirtyRect> {
+	assert_eq!(previous.len(), current.len());
+
+	DirtyRect::from_pixels(
+		previous.iter().zip(current)
+			.enumerate()
+			.filter(|(_, (a, b))| a != b)
+			.map(|(i, _)| (i % width, i / width))
+	)
+}
+
+/// Merges separately rendered layers, each a color and a depth buffer of the same size (see
+/// `render::render_depth`). Every pixel takes the color of the layer nearest to the camera
+/// there, the first layer wins ties. Panics if any buffer differs in size from the first color
+/// buffer.
+pub fn composite_by_depth(layers: &[(Vec<u32>, Vec<f32>)]) -> Vec<u32> {
+	let Some((first, _)) = layers.first() else {
+		return Vec::new();
+	};
+	for (color, depth) in layers {
+		assert!(color.len() == first.len() && depth.len() == first.len(), "layers differ in size");
+	}
+
+	(0..first.len()).map(|i| {
+		let (nearest, _) = layers.iter()
+			.map(|(color, depth)| (color[i], depth[i]))
+			.fold((first[i], f32::INFINITY), |best, layer| if layer.1 < best.1 { layer } else { best });
+		nearest
+	}).collect()
+}
+
+/// Writes a march trace as CSV, one row per step with the position, the distance `scene`
+/// reported there and the depth along the ray, for plotting.
+pub fn write_march_trace(steps: &[MarchStep], path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+	use std::io::Write;
+
+	let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+	writeln!(file, "step,x,y,z,distance,depth")?;
+	for (i, step) in steps.iter().enumerate() {
+		let p = step.position;
+		writeln!(file, "{},{},{},{},{},{}", i, p.x, p.y, p.z, step.distance, step.depth)?;
+	}
+
+	file.flush()
+}
+
+/// Converts a premultiplied ARGB pixel as written by the renderer to straight RGBA bytes.
+pub fn unpack_straight(pixel: u32) -> [u8; 4] {
+	let a = (pixel >> 24) as u8;
+	let unpremultiply = |c: u32| {
+		if a == 0 {
+			0
+		} else {
+			((c & 0xff) * 255 / a as u32).min(255) as u8
+		}
+	};
+
+	[unpremultiply(pixel >> 16), unpremultiply(pixel >> 8), unpremultiply(pixel), a]
+}
+
+/// Reverses the row order of a framebuffer. The renderer writes the top row first, some
+/// formats and tools expect the bottom row first.
+pub fn flip_rows<T: Copy>(buffer: &[T], width: usize) -> Vec<T> {
+	if width == 0 {
+		return buffer.to_vec();
+	}
+
+	buffer.chunks(width).rev().flatten().copied().collect()
+}
+
+/// Encodes a rendered framebuffer as a PNG in memory, bottom row first if `flip_y` is set.
+#[cfg(feature = "image")]
+pub fn encode_png(buffer: &[u32], width: usize, height: usize, flip_y: bool) -> Vec<u8> {
+	use image::ImageEncoder;
+
+	assert_eq!(buffer.len(), width * height, "buffer does not match the image size");
+
+	let flipped;
+	let buffer = if flip_y {
+		flipped = flip_rows(buffer, width);
+		&flipped
+	} else {
+		buffer
+	};
+
+	let rgba: Vec<u8> = buffer.iter().flat_map(|&pixel| unpack_straight(pixel)).collect();
+	let mut png = Vec::new();
+
+	image::codecs::png::PngEncoder::new(&mut png)
+		.write_image(&rgba, width as u32, height as u32, image::ColorType::Rgba8)
+		.expect("encoding a PNG into memory failed");
+
+	png
+}
+
+/// Encodes an HDR buffer as a 16 bit per channel RGB PNG in memory, bottom row first if
+/// `flip_y` is set. Colors are tone mapped with `tone_map_reinhard` and encoded in `colorspace`
+/// first. Keeps smooth gradients that band at 8 bits, e.g. for grading later.
+#[cfg(feature = "image")]
+pub fn encode_png16(hdr: &[Vector3], width: usize, height: usize, colorspace: ColorSpace, flip_y: bool) -> Vec<u8> {
+	use image::ImageEncoder;
+
+	assert_eq!(hdr.len(), width * height, "buffer does not match the image size");
+
+	let flipped;
+	let hdr = if flip_y {
+		flipped = flip_rows(hdr, width);
+		&flipped
+	} else {
+		hdr
+	};
+
+	let rgb: Vec<u8> = hdr.iter()
+		.map(|&c| colorspace.encode(color::tone_map_reinhard(c)))
+		.flat_map(|c| [c.x, c.y, c.z])
+		.flat_map(|c| ((c.clamp(0.0, 1.0) * 65535.0).round() as u16).to_ne_bytes())
+		.collect();
+	let mut png = Vec::new();
+
+	image::codecs::png::PngEncoder::new(&mut png)
+		.write_image(&rgb, width as u32, height as u32, image::ColorType::Rgb16)
+		.expect("encoding a PNG into memory failed");
+
+	png
+}
+
+/// Renders the demo scene from the default camera and writes it to `path` as a PNG, without
+/// opening a window, e.g. on a headless server. Bottom row first if `flip_y` is set. With the
+/// `serde` feature a `RenderMetadata` sidecar is written next to the image as well.
+#[cfg(feature = "image")]
+pub fn render_to_image(width: usize, height: usize, path: impl AsRef<std::path::Path>, flip_y: bool) -> std::io::Result<()> {
+	let camera = Camera::default();
+	let params = RenderParams::default();
+	#[cfg_attr(not(feature = "serde"), allow(unused_variables))]
+	let (buffer, stats) = render::render_with_stats(width, height, &camera, &|p| scene(p, 1.0), &params);
+
+	std::fs::write(&path, encode_png(&buffer, width, height, flip_y))?;
+
+	#[cfg(feature = "serde")]
+	RenderMetadata {
+		camera,
+		width,
+		height,
+		samples: params.aa_samples * params.aa_samples,
+		scene: "demo".to_string(),
+		stats,
+	}.write(&path)?;
+
+	Ok(())
+}
+
+/// Renders a frame and returns it as a base64 encoded PNG, e.g. for embedding in a notebook.
+#[cfg(all(feature = "image", feature = "base64"))]
+pub fn render_to_png_base64(width: usize, height: usize, camera: &Camera, scene: &(impl Fn(Vector3) -> f64 + Sync), params: &RenderParams, flip_y: bool) -> String {
+	let buffer = render::render(width, height, camera, scene, params);
+
+	base64::encode(encode_png(&buffer, width, height, flip_y))
+}
+
+/// How a render was made, stored next to the image by `RenderMetadata::write`.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RenderMetadata {
+	pub camera: Camera,
+	pub width: usize,
+	pub height: usize,
+	/// Samples per pixel.
+	pub samples: usize,
+	/// Name or hash identifying the scene.
+	pub scene: String,
+	pub stats: RenderStats,
+}
+
+#[cfg(feature = "serde")]
+impl RenderMetadata {
+	/// Where the metadata for `image_path` lives, the image path with a `.json` extension.
+	pub fn sidecar_path(image_path: impl AsRef<std::path::Path>) -> std::path::PathBuf {
+		image_path.as_ref().with_extension("json")
+	}
+
+	/// Writes the metadata next to the image at `image_path`.
+	pub fn write(&self, image_path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+		let file = std::fs::File::create(RenderMetadata::sidecar_path(image_path))?;
+		serde_json::to_writer_pretty(file, self)?;
+		Ok(())
+	}
+
+	/// Reads back the metadata written for the image at `image_path`.
+	pub fn read(image_path: impl AsRef<std::path::Path>) -> std::io::Result<RenderMetadata> {
+		let file = std::fs::File::open(RenderMetadata::sidecar_path(image_path))?;
+		Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[cfg(feature = "image")]
+	fn decode(png: &[u8]) -> image::DynamicImage {
+		image::load_from_memory_with_format(png, image::ImageFormat::Png).expect("not a valid PNG")
+	}
+
+	#[cfg(all(feature = "image", feature = "base64"))]
+	#[test]
+	fn base64_png_decodes_to_the_render_size() {
+		let encoded = render_to_png_base64(12, 7, &Camera::default(), &sdf::sphere(1.5), &RenderParams::default(), false);
+		let png = base64::decode(encoded).expect("not valid base64");
+
+		let image = decode(&png);
+		assert_eq!((image.width(), image.height()), (12, 7));
+	}
+
+	#[test]
+	fn dirty_rect_bounds_the_changed_pixels() {
+		assert_eq!(DirtyRect::from_pixels(vec![]), None);
+		assert_eq!(
+			DirtyRect::from_pixels(vec![(3, 1), (5, 4), (4, 2)]),
+			Some(DirtyRect { x: 3, y: 1, width: 3, height: 4 })
+		);
+
+		let previous = vec![0; 4 * 3];
+		let mut current = previous.clone();
+		assert_eq!(dirty_rect(&previous, &current, 4), None);
+
+		current[1 + 2 * 4] = 7;
+		assert_eq!(dirty_rect(&previous, &current, 4), Some(DirtyRect { x: 1, y: 2, width: 1, height: 1 }));
+	}
+
+	#[test]
+	fn flip_rows_reverses_the_row_order() {
+		assert_eq!(flip_rows(&[1, 2, 3, 4, 5, 6], 2), vec![5, 6, 3, 4, 1, 2]);
+		assert_eq!(flip_rows(&[1, 2, 3], 3), vec![1, 2, 3]);
+		assert_eq!(flip_rows::<u32>(&[], 0), Vec::<u32>::new());
+	}
+
+	#[cfg(feature = "image")]
+	#[test]
+	fn png16_keeps_gradients_that_band_at_8_bits() {
+		// a gradient far too shallow for 256 levels
+		let hdr: Vec<Vector3> = (0..64).map(|i| Vector3::new(0.5 + i as f64 * 1e-4, 0.5, 0.5)).collect();
+
+		let image = decode(&encode_png16(&hdr, 64, 1, ColorSpace::Linear, false));
+		assert_eq!(image.color(), image::ColorType::Rgb16);
+
+		let red: Vec<u16> = image.to_rgb16().pixels().map(|p| p[0]).collect();
+		let distinct = |values: Vec<u16>| values.windows(2).filter(|w| w[0] != w[1]).count();
+
+		assert!(red.windows(2).all(|w| w[0] <= w[1]));
+		assert!(distinct(red.clone()) > 4 * distinct(red.iter().map(|v| v >> 8).collect()));
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn metadata_round_trips_through_the_sidecar() {
+		let image = std::env::temp_dir().join(format!("raymarcher-metadata-{}.png", std::process::id()));
+		let metadata = RenderMetadata {
+			camera: Camera::default(),
+			width: 64,
+			height: 48,
+			samples: 4,
+			scene: "spheres".to_string(),
+			stats: RenderStats { rays: 12288, hits: 1000, mean_steps: 7.5, ..RenderStats::default() },
+		};
+
+		metadata.write(&image).unwrap();
+		let loaded = RenderMetadata::read(&image).unwrap();
+		std::fs::remove_file(RenderMetadata::sidecar_path(&image)).unwrap();
+
+		assert_eq!((loaded.width, loaded.height, loaded.samples), (64, 48, 4));
+		assert_eq!(loaded.scene, "spheres");
+		assert_eq!((loaded.stats.rays, loaded.stats.hits, loaded.stats.mean_steps), (12288, 1000, 7.5));
+		assert_eq!(loaded.camera.fov, metadata.camera.fov);
+	}
+
+	#[cfg(all(feature = "image", feature = "serde"))]
+	#[test]
+	fn render_to_image_writes_a_sidecar() {
+		let image = std::env::temp_dir().join(format!("raymarcher-sidecar-{}.png", std::process::id()));
+
+		render_to_image(8, 6, &image, false).unwrap();
+		let metadata = RenderMetadata::read(&image).unwrap();
+		std::fs::remove_file(&image).unwrap();
+		std::fs::remove_file(RenderMetadata::sidecar_path(&image)).unwrap();
+
+		assert_eq!((metadata.width, metadata.height), (8, 6));
+		assert_eq!(metadata.stats.rays, 48);
+	}
+
+	#[test]
+	fn nearer_layers_occlude_farther_ones() {
+		let inf = f32::INFINITY;
+		let back = (vec![1, 1, 1, 1], vec![5.0, 5.0, inf, 2.0]);
+		let front = (vec![2, 2, 2, 2], vec![3.0, inf, inf, 2.0]);
+
+		// the front covers the first pixel, the back shows through where the front misses, and
+		// the first layer wins ties and pixels neither layer hits
+		assert_eq!(composite_by_depth(&[back, front]), vec![2, 1, 1, 1]);
+		assert!(composite_by_depth(&[]).is_empty());
+	}
+
+	#[cfg(feature = "image")]
+	#[test]
+	fn render_to_image_writes_a_decodable_png() {
+		let path = std::env::temp_dir().join(format!("raymarcher-headless-{}.png", std::process::id()));
+
+		render_to_image(16, 16, &path, false).unwrap();
+		let png = std::fs::read(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		#[cfg(feature = "serde")]
+		std::fs::remove_file(RenderMetadata::sidecar_path(&path)).unwrap();
+
+		let image = decode(&png);
+		assert_eq!((image.width(), image.height()), (16, 16));
+	}
+
+	#[test]
+	fn march_trace_is_written_as_csv() {
+		let ray = Ray::new(Vector3::new(0.0, 0.0, -10.0), Vector3::new(0.0, 0.0, 1.0));
+		let trace = render::march_trace(&ray, &sdf::sphere(1.5), 1.0, 0);
+		let path = std::env::temp_dir().join(format!("raymarcher-trace-{}.csv", std::process::id()));
+
+		write_march_trace(&trace, &path).unwrap();
+		let csv = std::fs::read_to_string(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+
+		let rows: Vec<Vec<f64>> = csv.lines().skip(1).map(|line| line.split(',').map(|v| v.parse().unwrap()).collect()).collect();
+		assert_eq!(csv.lines().next(), Some("step,x,y,z,distance,depth"));
+		assert_eq!(rows.len(), trace.len());
+		assert!(rows.windows(2).all(|w| w[1][5] > w[0][5]));
+		assert!(rows.last().unwrap()[4] < EPSILON);
+	}
+}
@@ -0,0 +1,427 @@
+use super::*;
+
+/// Bilinearly samples `buffer` at the (fractional) pixel coordinate `(x, y)`, clamping to the edges.
+fn sample(buffer: &[Vector3], width: usize, height: usize, x: f64, y: f64) -> Vector3 {
+	let x = x.clamp(0.0, (width - 1) as f64);
+	let y = y.clamp(0.0, (height - 1) as f64);
+	let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+	let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+	let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+
+	let top = buffer[x0 + y0 * width] * (1.0 - fx) + buffer[x1 + y0 * width] * fx;
+	let bottom = buffer[x0 + y1 * width] * (1.0 - fx) + buffer[x1 + y1 * width] * fx;
+	top * (1.0 - fy) + bottom * fy
+}
+
+/// Fakes lateral chromatic aberration by sampling red slightly further out from the image
+/// center and blue slightly further in, green stays put. The offset is proportional to the
+/// distance from the center, so the center pixel is untouched and the corners fringe the most.
+/// `strength` is the relative offset, e.g. `0.01` shifts red and blue by 1% of the radius.
+pub fn apply_chromatic_aberration(hdr: &[Vector3], width: usize, height: usize, strength: f64) -> Vec<Vector3> {
+	assert_eq!(hdr.len(), width * height);
+
+	let cx = (width as f64 - 1.0) / 2.0;
+	let cy = (height as f64 - 1.0) / 2.0;
+	let mut out = Vec::with_capacity(hdr.len());
+
+	for y in 0..height {
+		for x in 0..width {
+			let (dx, dy) = (x as f64 - cx, y as f64 - cy);
+
+			let red = sample(hdr, width, height, cx + dx * (1.0 + strength), cy + dy * (1.0 + strength));
+			let blue = sample(hdr, width, height, cx + dx * (1.0 - strength), cy + dy * (1.0 - strength));
+
+			out.push(Vector3::new(red.x, hdr[x + y * width].y, blue.z));
+		}
+	}
+
+	out
+}
+
+/// Depth difference below which a neighbor isn't considered in front of a pixel.
+const SSAO_BIAS: f32 = 0.02;
+
+/// Screen-space ambient occlusion from a depth and normal buffer, as a post-process. Each
+/// pixel looks at `samples` neighbors on a spiral within `radius` pixels and counts the ones
+/// closer to the camera. Neighbors with the same normal are taken to be on the same flat
+/// surface and skipped, so slanted planes don't occlude themselves. Closer occluders count
+/// more than ones far in front. Returns occlusion from 0.0 (open) to 1.0, background pixels
+/// (non-finite depth) are 0.0.
+pub fn ssao(depth: &[f32], normals: &[[f32; 3]], width: usize, height: usize, radius: f64, samples: usize) -> Vec<f32> {
+	assert_eq!(depth.len(), width * height);
+	assert_eq!(normals.len(), width * height);
+
+	// golden angle spiral, evenly covers the disc without any randomness
+	let offsets: Vec<(f64, f64)> = (0..samples).map(|i| {
+		let angle = i as f64 * 2.399_963;
+		let r = radius * ((i as f64 + 0.5) / samples as f64).sqrt();
+		(angle.cos() * r, angle.sin() * r)
+	}).collect();
+
+	let mut occlusion = Vec::with_capacity(depth.len());
+
+	for y in 0..height {
+		for x in 0..width {
+			let i = x + y * width;
+			let d = depth[i];
+			if !d.is_finite() || samples == 0 {
+				occlusion.push(0.0);
+				continue;
+			}
+
+			let n = normals[i];
+			let mut occluded = 0.0;
+
+			for &(dx, dy) in &offsets {
+				let sx = (x as f64 + dx).round().clamp(0.0, (width - 1) as f64) as usize;
+				let sy = (y as f64 + dy).round().clamp(0.0, (height - 1) as f64) as usize;
+				let j = sx + sy * width;
+
+				let delta = d - depth[j];
+				let m = normals[j];
+				let same_surface = n[0] * m[0] + n[1] * m[1] + n[2] * m[2] > 0.99;
+
+				if delta > SSAO_BIAS && !same_surface {
+					occluded += 1.0 / (1.0 + delta * delta);
+				}
+			}
+
+			occlusion.push(occluded / samples as f32);
+		}
+	}
+
+	occlusion
+}
+
+fn luminance(c: Vector3) -> f64 {
+	0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+/// Edges between neighboring pixels whose luminance differs by more than `threshold`, as
+/// `(right, below)` per pixel: whether there's an edge to the pixel on the right and the
+/// pixel below.
+pub fn edge_mask(hdr: &[Vector3], width: usize, height: usize, threshold: f64) -> Vec<(bool, bool)> {
+	assert_eq!(hdr.len(), width * height);
+
+	let differs = |a: usize, b: usize| (luminance(hdr[a]) - luminance(hdr[b])).abs() > threshold;
+
+	(0..width * height).map(|i| {
+		let (x, y) = (i % width, i / width);
+		(x + 1 < width && differs(i, i + 1), y + 1 < height && differs(i, i + width))
+	}).collect()
+}
+
+/// Morphological anti-aliasing: finds edges with `edge_mask` and blends the two pixels on
+/// each side of an edge into each other. Straight runs of edge pixels are blended the most in
+/// their middle and fade out at their ends, so staircases along diagonals get smoothed while
+/// long straight edges stay crisp. Pixels away from edges are untouched.
+pub fn apply_mlaa(hdr: &[Vector3], width: usize, height: usize, threshold: f64) -> Vec<Vector3> {
+	let edges = edge_mask(hdr, width, height, threshold);
+	let mut out = hdr.to_vec();
+
+	// blends pixel `a` and its neighbor `b` across the edge, `pos` of `len` pixels into a run
+	let mut blend = |a: usize, b: usize, pos: usize, len: usize| {
+		let t = (pos as f64 + 0.5) / len as f64;
+		let weight = 0.5 * (1.0 - (2.0 * t - 1.0).abs());
+		out[a] = out[a].lerp(hdr[b], weight / 2.0);
+		out[b] = out[b].lerp(hdr[a], weight / 2.0);
+	};
+
+	// edges below a pixel, runs along x
+	for y in 0..height {
+		let mut x = 0;
+		while x < width {
+			if !edges[x + y * width].1 {
+				x += 1;
+				continue;
+			}
+
+			let start = x;
+			while x < width && edges[x + y * width].1 {
+				x += 1;
+			}
+			for pos in 0..x - start {
+				let i = start + pos + y * width;
+				blend(i, i + width, pos, x - start);
+			}
+		}
+	}
+
+	// edges to the right of a pixel, runs along y
+	for x in 0..width {
+		let mut y = 0;
+		while y < height {
+			if !edges[x + y * width].0 {
+				y += 1;
+				continue;
+			}
+
+			let start = y;
+			while y < height && edges[x + y * width].0 {
+				y += 1;
+			}
+			for pos in 0..y - start {
+				let i = x + (start + pos) * width;
+				blend(i, i + 1, pos, y - start);
+			}
+		}
+	}
+
+	out
+}
+
+/// A 3D color lookup table, `size` entries per axis with red varying fastest, mapping colors in
+/// `domain_min..domain_max` to new colors.
+#[derive(Clone, Debug)]
+pub struct Lut3D {
+	pub size: usize,
+	pub domain_min: Vector3,
+	pub domain_max: Vector3,
+	pub table: Vec<Vector3>,
+}
+
+impl Lut3D {
+	/// A LUT that maps every color in `[0, 1]` to itself.
+	pub fn identity(size: usize) -> Self {
+		let size = size.max(2);
+		let step = 1.0 / (size - 1) as f64;
+		let mut table = Vec::with_capacity(size * size * size);
+
+		for b in 0..size {
+			for g in 0..size {
+				for r in 0..size {
+					table.push(Vector3::new(r as f64 * step, g as f64 * step, b as f64 * step));
+				}
+			}
+		}
+
+		Lut3D { size, domain_min: Vector3::new(0.0, 0.0, 0.0), domain_max: Vector3::new(1.0, 1.0, 1.0), table }
+	}
+
+	/// Parses an Adobe `.cube` 3D LUT.
+	pub fn from_cube_file(path: impl AsRef<std::path::Path>) -> std::io::Result<Lut3D> {
+		Lut3D::parse_cube(&std::fs::read_to_string(path)?)
+	}
+
+	pub fn parse_cube(source: &str) -> std::io::Result<Lut3D> {
+		let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+		let parse_vector = |line: &str, values: std::str::SplitWhitespace| {
+			let values: Vec<f64> = values.map(str::parse).collect::<Result<_, _>>()
+				.map_err(|_| invalid(format!("invalid number in `{}`", line)))?;
+			match values[..] {
+				[r, g, b] => Ok(Vector3::new(r, g, b)),
+				_ => Err(invalid(format!("expected three values in `{}`", line))),
+			}
+		};
+
+		let mut size = None;
+		let mut domain_min = Vector3::new(0.0, 0.0, 0.0);
+		let mut domain_max = Vector3::new(1.0, 1.0, 1.0);
+		let mut table = Vec::new();
+
+		for line in source.lines().map(str::trim) {
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+
+			let mut words = line.split_whitespace();
+			match words.next() {
+				Some("LUT_1D_SIZE") => return Err(invalid("1D LUTs are not supported".to_string())),
+				Some("LUT_3D_SIZE") => {
+					size = Some(words.next().and_then(|n| n.parse::<usize>().ok())
+						.filter(|&n| n >= 2)
+						.ok_or_else(|| invalid(format!("invalid size in `{}`", line)))?);
+				}
+				Some("DOMAIN_MIN") => domain_min = parse_vector(line, words)?,
+				Some("DOMAIN_MAX") => domain_max = parse_vector(line, words)?,
+				// TITLE and whatever else a tool adds, like LUT_3D_INPUT_RANGE, change nothing here
+				Some(keyword) if keyword.parse::<f64>().is_err() => {}
+				_ => table.push(parse_vector(line, line.split_whitespace())?),
+			}
+		}
+
+		let size = size.ok_or_else(|| invalid("missing LUT_3D_SIZE".to_string()))?;
+		if table.len() != size * size * size {
+			return Err(invalid(format!("expected {} entries, found {}", size * size * size, table.len())));
+		}
+
+		Ok(Lut3D { size, domain_min, domain_max, table })
+	}
+
+	fn entry(&self, r: usize, g: usize, b: usize) -> Vector3 {
+		self.table[r + (g + b * self.size) * self.size]
+	}
+
+	/// Looks up `color` with trilinear interpolation, colors outside the domain are clamped.
+	pub fn sample(&self, color: Vector3) -> Vector3 {
+		let max = (self.size - 1) as f64;
+		let coord = |c: f64, min: f64, range_max: f64| ((c - min) / (range_max - min)).clamp(0.0, 1.0) * max;
+		let (r, g, b) = (
+			coord(color.x, self.domain_min.x, self.domain_max.x),
+			coord(color.y, self.domain_min.y, self.domain_max.y),
+			coord(color.z, self.domain_min.z, self.domain_max.z),
+		);
+
+		let (r0, g0, b0) = (r.floor() as usize, g.floor() as usize, b.floor() as usize);
+		let (r1, g1, b1) = ((r0 + 1).min(self.size - 1), (g0 + 1).min(self.size - 1), (b0 + 1).min(self.size - 1));
+		let (fr, fg, fb) = (r - r0 as f64, g - g0 as f64, b - b0 as f64);
+
+		let face = |b| {
+			let bottom = self.entry(r0, g0, b).lerp(self.entry(r1, g0, b), fr);
+			let top = self.entry(r0, g1, b).lerp(self.entry(r1, g1, b), fr);
+			bottom.lerp(top, fg)
+		};
+
+		face(b0).lerp(face(b1), fb)
+	}
+}
+
+/// Color grades an image through `lut`.
+pub fn apply_lut(hdr: &[Vector3], lut: &Lut3D) -> Vec<Vector3> {
+	hdr.iter().map(|&c| lut.sample(c)).collect()
+}
+
+/// Illustration look: draws `line_color` along every edge found by `edge_mask`, the lines
+/// `thickness` pixels wide and centered on the edge. The extra pixel of odd thicknesses goes
+/// to the left of or above the edge, 0 draws no lines. The rest of the image keeps its shading.
+pub fn apply_edge_lines(hdr: &[Vector3], width: usize, height: usize, threshold: f64, line_color: Vector3, thickness: usize) -> Vec<Vector3> {
+	let edges = edge_mask(hdr, width, height, threshold);
+	let mut out = hdr.to_vec();
+
+	// an edge lies between a pixel and its neighbor, the line covers `before` pixels up to and
+	// including the first one and `after` pixels from the neighbor on
+	let (before, after) = (thickness.div_ceil(2) as isize, (thickness / 2) as isize);
+	let mut paint = |x: isize, y: isize| {
+		if (0..width as isize).contains(&x) && (0..height as isize).contains(&y) {
+			out[(x + y * width as isize) as usize] = line_color;
+		}
+	};
+
+	for (i, &(right, below)) in edges.iter().enumerate() {
+		let (x, y) = ((i % width) as isize, (i / width) as isize);
+
+		for offset in 1 - before..=after {
+			if right {
+				paint(x + offset, y);
+			}
+			if below {
+				paint(x, y + offset);
+			}
+		}
+	}
+
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const BLACK: Vector3 = Vector3 { x: 0.0, y: 0.0, z: 0.0 };
+	const WHITE: Vector3 = Vector3 { x: 1.0, y: 1.0, z: 1.0 };
+
+	#[test]
+	fn aberration_spares_the_center_and_fringes_the_edges() {
+		// white center pixel and a white column near the right edge on black
+		let (width, height) = (9, 9);
+		let hdr: Vec<Vector3> = (0..width * height)
+			.map(|i| if i == 4 + 4 * width || i % width == 7 { WHITE } else { BLACK })
+			.collect();
+
+		let out = apply_chromatic_aberration(&hdr, width, height, 0.2);
+
+		let center = out[4 + 4 * width];
+		assert!((center - WHITE).magnitude() < 1e-9);
+
+		let edge = out[7 + 4 * width];
+		assert!((edge.x - edge.y).abs() > 0.1 || (edge.z - edge.y).abs() > 0.1, "{:?}", edge);
+	}
+
+	#[test]
+	fn ssao_darkens_the_foot_of_a_step() {
+		// a far wall on the left half and a closer block facing sideways on the right half
+		let (width, height) = (16, 8);
+		let depth: Vec<f32> = (0..width * height).map(|i| if i % width < 8 { 10.0 } else { 9.0 }).collect();
+		let normals: Vec<[f32; 3]> = (0..width * height).map(|i| if i % width < 8 { [0.0, 0.0, -1.0] } else { [-1.0, 0.0, 0.0] }).collect();
+
+		let occlusion = ssao(&depth, &normals, width, height, 3.0, 16);
+		let at = |x: usize| occlusion[x + 4 * width];
+
+		assert!(at(7) > 0.1, "{}", at(7));
+		assert_eq!(at(0), 0.0);
+		assert_eq!(at(12), 0.0);
+	}
+
+	#[test]
+	fn mlaa_softens_a_diagonal_and_leaves_flat_regions() {
+		// white above the diagonal, black below, a staircase at one step per pixel
+		let size = 8;
+		let hdr: Vec<Vector3> = (0..size * size).map(|i| if i % size > i / size { WHITE } else { BLACK }).collect();
+
+		let out = apply_mlaa(&hdr, size, size, 0.5);
+
+		assert!(out.iter().any(|c| c.x > 0.05 && c.x < 0.95));
+		assert!((out[size - 1] - WHITE).magnitude() < 1e-9);
+		assert!((out[(size - 1) * size] - BLACK).magnitude() < 1e-9);
+
+		let flat = vec![WHITE; size * size];
+		assert!(apply_mlaa(&flat, size, size, 0.5).iter().all(|c| (*c - WHITE).magnitude() < 1e-9));
+	}
+
+	#[test]
+	fn identity_lut_leaves_colors_alone() {
+		let lut = Lut3D::identity(5);
+		let colors = [Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.3, 0.7, 0.1), WHITE];
+
+		for (graded, original) in apply_lut(&colors, &lut).iter().zip(&colors) {
+			assert!((*graded - *original).magnitude() < 1e-9);
+		}
+	}
+
+	#[test]
+	fn parses_cube_files() {
+		// an inverting 2x2x2 LUT, red varying fastest
+		let source = "\
+			# comment\n\
+			TITLE \"invert\"\n\
+			LUT_3D_SIZE 2\n\
+			\n\
+			1 1 1\n0 1 1\n1 0 1\n0 0 1\n\
+			1 1 0\n0 1 0\n1 0 0\n0 0 0\n";
+
+		let lut = Lut3D::parse_cube(source).unwrap();
+		assert_eq!(lut.size, 2);
+		assert!((lut.sample(Vector3::new(1.0, 0.0, 0.25)) - Vector3::new(0.0, 1.0, 0.75)).magnitude() < 1e-9);
+
+		assert!(Lut3D::parse_cube("LUT_3D_SIZE 2\n0 0 0\n").is_err());
+		assert!(Lut3D::parse_cube("LUT_3D_SIZE 2\n0 0\n").is_err());
+		assert!(Lut3D::parse_cube("0 0 0\n").is_err());
+	}
+
+	#[test]
+	fn unknown_cube_keywords_are_ignored() {
+		let source = "LUT_3D_SIZE 2\nLUT_3D_INPUT_RANGE 0 1\n0 0 0\n1 0 0\n0 1 0\n1 1 0\n0 0 1\n1 0 1\n0 1 1\n1 1 1\n";
+
+		let lut = Lut3D::parse_cube(source).unwrap();
+		assert!((lut.sample(Vector3::new(0.25, 0.5, 0.75)) - Vector3::new(0.25, 0.5, 0.75)).magnitude() < 1e-9);
+	}
+
+	#[test]
+	fn edge_lines_are_as_thick_as_requested() {
+		// black left half, white right half, the edge between columns 3 and 4
+		let (width, height) = (8, 4);
+		let hdr: Vec<Vector3> = (0..width * height).map(|i| if i % width < 4 { BLACK } else { WHITE }).collect();
+		let red = Vector3::new(1.0, 0.0, 0.0);
+		let is_red = |c: &Vector3| (*c - red).magnitude() < 1e-9;
+
+		for (thickness, columns) in [(0, 0..0), (1, 3..4), (2, 3..5), (3, 2..5), (4, 2..6)] {
+			let out = apply_edge_lines(&hdr, width, height, 0.5, red, thickness);
+
+			for (i, c) in out.iter().enumerate() {
+				assert_eq!(is_red(c), columns.contains(&(i % width)), "thickness {} at {}", thickness, i);
+			}
+			// the interior keeps its shading
+			assert!((out[0] - BLACK).magnitude() < 1e-9 && (out[width - 1] - WHITE).magnitude() < 1e-9);
+		}
+	}
+}
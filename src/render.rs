@@ -0,0 +1,1679 @@
+use super::*;
+
+use color::ColorSpace;
+use noise::Rng;
+use rayon::prelude::*;
+use std::ops::Range;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+pub const MAX_STEPS: usize = 50;
+/// Rays that get further than this from their origin without hitting anything are misses.
+pub const MAX_DISTANCE: f64 = 100.0;
+
+const AO_SAMPLES: usize = 5;
+const AO_STEP: f64 = 0.1;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Material {
+	pub albedo: Vector3,
+	/// Perceptual roughness in `[0, 1]`, only used by `Shading::CookTorrance`.
+	pub roughness: f64,
+	/// 0.0 for dielectrics, 1.0 for metals, only used by `Shading::CookTorrance`.
+	pub metallic: f64,
+}
+
+impl Default for Material {
+	fn default() -> Self {
+		Material {
+			albedo: Vector3::new(1.0, 0.0, 0.0),
+			roughness: 0.5,
+			metallic: 0.0,
+		}
+	}
+}
+
+impl Material {
+	/// Blends towards `other`, `t = 0.0` is `self` and `t = 1.0` is `other`.
+	pub fn mix(&self, other: &Material, t: f64) -> Material {
+		Material {
+			albedo: self.albedo.lerp(other.albedo, t),
+			roughness: self.roughness + (other.roughness - self.roughness) * t,
+			metallic: self.metallic + (other.metallic - self.metallic) * t,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shading {
+	Lambert,
+	CookTorrance,
+	/// Debug view coloring every hit by its normal, `normal * 0.5 + 0.5`.
+	Normals,
+}
+
+/// Presets for `RenderParams::step_scale`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quality {
+	Draft,
+	Normal,
+	High,
+}
+
+impl Quality {
+	pub fn step_scale(self) -> f64 {
+		match self {
+			Quality::Draft => 1.6,
+			Quality::Normal => 1.0,
+			Quality::High => 0.8,
+		}
+	}
+}
+
+/// Paints silhouettes, i.e. hits whose `facing_ratio` is below `threshold`, in `color`.
+#[derive(Clone, Copy, Debug)]
+pub struct Outline {
+	pub threshold: f64,
+	pub color: Vector3,
+}
+
+/// Reference grid drawn on the horizontal ground plane at `height`, one line per world unit.
+#[derive(Clone, Copy, Debug)]
+pub struct Grid {
+	pub height: f64,
+	pub line_width: f64,
+}
+
+/// Albedo multiplier for grid lines.
+const GRID_LINE_DARKEN: f64 = 0.25;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Light {
+	pub position: Vector3,
+	pub strength: f64,
+	pub color: Vector3,
+	/// Radius of the (spherical) light. Bigger lights cast softer shadows, 0.0 is a point
+	/// light with hard shadows.
+	pub radius: f64,
+}
+
+impl Default for Light {
+	fn default() -> Self {
+		Light {
+			position: Vector3::new(4.0, 3.0, -6.0),
+			strength: 10.0,
+			color: Vector3::new(1.0, 1.0, 1.0),
+			radius: 0.0,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RenderParams {
+	pub shading: Shading,
+	pub material: Material,
+	/// Constant light added to every hit, so surfaces facing away from the light aren't black.
+	pub ambient: Vector3,
+	/// Multiplier for every march step, see `march`.
+	pub step_scale: f64,
+	/// Steps grazing rays may take on top of `MAX_STEPS`, see `march_adaptive`.
+	pub extra_steps: usize,
+	pub outline: Option<Outline>,
+	/// Debug aid: paints pixels whose ray starts inside geometry in this color.
+	pub inside_color: Option<Vector3>,
+	pub grid: Option<Grid>,
+	pub light: Light,
+	pub shadows: bool,
+	/// Darkens `ambient` in creases by `ambient_occlusion`.
+	pub occlusion: bool,
+	/// How far secondary rays start off the surface, see `Ray::from_surface`.
+	pub surface_bias: f64,
+	/// Seeds everything random in a render, see `pixel_rng`. The same scene, camera, params
+	/// and seed always produce the same image.
+	pub seed: u64,
+	/// Encoding applied to colors when they are packed into the output buffer.
+	pub output_colorspace: ColorSpace,
+	/// Packed pixel written wherever no geometry is hit, and what `render_into` clears the
+	/// buffer to before drawing. Transparent by default.
+	pub clear_color: u32,
+	/// Sub-pixel positions primary rays go through instead of the pixel centers, see `OffsetTile`.
+	pub pixel_offsets: Option<OffsetTile>,
+	/// Tone maps with `tone_map_reinhard_white` at this white point before encoding, colors are
+	/// just clamped without one.
+	pub white_point: Option<f64>,
+	/// Antialias silhouettes analytically: rays that narrowly miss get the color of the surface
+	/// they pass, weighted by `edge_coverage`. Costs a second march for missed pixels only.
+	pub analytic_aa: bool,
+	/// Supersampling: every pixel averages an `aa_samples` x `aa_samples` grid of jittered
+	/// rays. 1 casts the single ray per pixel of `primary_ray`.
+	pub aa_samples: usize,
+}
+
+impl Default for RenderParams {
+	fn default() -> Self {
+		RenderParams {
+			shading: Shading::Lambert,
+			material: Material::default(),
+			ambient: Vector3::new(0.04, 0.04, 0.04),
+			step_scale: Quality::Normal.step_scale(),
+			extra_steps: 0,
+			outline: None,
+			inside_color: None,
+			grid: None,
+			light: Light::default(),
+			shadows: false,
+			occlusion: true,
+			surface_bias: SURFACE_BIAS,
+			seed: 0,
+			output_colorspace: ColorSpace::Linear,
+			white_point: None,
+			pixel_offsets: None,
+			clear_color: U8Color::TRANSPARENT.as_u32(),
+			analytic_aa: false,
+			aa_samples: 1,
+		}
+	}
+}
+
+/// Side length of an `OffsetTile`.
+pub const OFFSET_TILE_SIZE: usize = 4;
+
+/// Sub-pixel sample positions repeated over the image every `OFFSET_TILE_SIZE` pixels. Moving
+/// the single sample of neighboring pixels around breaks up the moire regular geometry gets
+/// from always sampling pixel centers, without casting more rays.
+#[derive(Clone, Copy, Debug)]
+pub struct OffsetTile {
+	/// Offsets within the pixel, each in `[0, 1)`, row by row.
+	pub offsets: [(f64, f64); OFFSET_TILE_SIZE * OFFSET_TILE_SIZE],
+}
+
+impl OffsetTile {
+	/// The tile's cell centers on a grid rotated by `atan(1/2)`, wrapped back into the pixel,
+	/// so no two pixels in a row or column of the tile share an offset.
+	pub fn rotated_grid() -> Self {
+		let n = OFFSET_TILE_SIZE as f64;
+		let (sin, cos) = 0.5f64.atan().sin_cos();
+		let mut offsets = [(0.0, 0.0); OFFSET_TILE_SIZE * OFFSET_TILE_SIZE];
+
+		for (i, offset) in offsets.iter_mut().enumerate() {
+			let u = ((i % OFFSET_TILE_SIZE) as f64 + 0.5) / n - 0.5;
+			let v = ((i / OFFSET_TILE_SIZE) as f64 + 0.5) / n - 0.5;
+			let wrap = |c: f64| (c + 0.5).rem_euclid(1.0);
+			*offset = (wrap(u * cos - v * sin), wrap(u * sin + v * cos));
+		}
+
+		OffsetTile { offsets }
+	}
+
+	pub fn offset(&self, x: usize, y: usize) -> (f64, f64) {
+		self.offsets[x % OFFSET_TILE_SIZE + (y % OFFSET_TILE_SIZE) * OFFSET_TILE_SIZE]
+	}
+}
+
+/// The primary ray for a pixel, through `params.pixel_offsets` if set.
+fn primary_ray(camera: &Camera, size: (usize, usize), (x, y): (usize, usize), params: &RenderParams) -> Ray {
+	match params.pixel_offsets {
+		Some(tile) => generate_primary_ray_at(camera, size, (x, y), tile.offset(x, y)),
+		None => generate_primary_ray(camera, size, (x, y)),
+	}
+}
+
+/// Random numbers for the pixel `(x, y)`. Depends only on the seed and the pixel, never on the
+/// order pixels are rendered in, so stochastic effects stay reproducible.
+pub fn pixel_rng(seed: u64, x: usize, y: usize) -> Rng {
+	let pixel = ((y as u64) << 32 | x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+	Rng::new(seed ^ pixel)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HitInfo {
+	pub position: Vector3,
+	pub normal: Vector3,
+	pub depth: f64,
+	pub steps: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RenderStats {
+	/// Primary rays cast, `width * height * aa_samples²` for a full frame.
+	pub rays: u64,
+	pub sdf_evaluations: u64,
+	pub hits: u64,
+	pub misses: u64,
+	pub min_steps: usize,
+	pub max_steps: usize,
+	pub mean_steps: f64,
+	pub elapsed: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum March {
+	Hit(HitInfo),
+	Miss { steps: usize },
+}
+
+impl March {
+	pub fn steps(&self) -> usize {
+		match self {
+			March::Hit(hit) => hit.steps,
+			March::Miss { steps } => *steps,
+		}
+	}
+}
+
+pub fn estimate_normal(scene: &impl Fn(Vector3) -> f64, pos: Vector3) -> Vector3 {
+	Vector3::new(
+		scene(pos + Vector3::new(EPSILON, 0.0, 0.0)) - scene(pos - Vector3::new(EPSILON, 0.0, 0.0)),
+		scene(pos + Vector3::new(0.0, EPSILON, 0.0)) - scene(pos - Vector3::new(0.0, EPSILON, 0.0)),
+		scene(pos + Vector3::new(0.0, 0.0, EPSILON)) - scene(pos - Vector3::new(0.0, 0.0, EPSILON))
+	).normalize()
+}
+
+/// Samples `scene` at increasing distances along `normal` and compares the result to the
+/// distance actually travelled. Open surfaces see `scene` grow as fast as the step, creases
+/// don't. Returns the occlusion in `[0, 1]`, 0 being fully exposed.
+pub fn ambient_occlusion(scene: &impl Fn(Vector3) -> f64, pos: Vector3, normal: Vector3) -> f64 {
+	let mut occlusion = 0.0;
+	let mut max_occlusion = 0.0;
+	let mut falloff = 1.0;
+
+	for i in 1..=AO_SAMPLES {
+		let step_dist = AO_STEP * i as f64;
+		occlusion += (step_dist - scene(pos + normal * step_dist)) * falloff;
+		max_occlusion += step_dist * falloff;
+		falloff *= 0.5;
+	}
+
+	(occlusion / max_occlusion).clamp(0.0, 1.0)
+}
+
+/// Single sample approximation of ambient occlusion: on an open surface `scene` grows by
+/// `delta` over a step of `delta` along the normal, in a concavity it grows less.
+/// Unlike `ambient_occlusion` this returns the visibility, 1.0 being fully exposed.
+pub fn cheap_ao(scene: &impl Fn(Vector3) -> f64, pos: Vector3, normal: Vector3, delta: f64) -> f64 {
+	(scene(pos + normal * delta) / delta).clamp(0.0, 1.0)
+}
+
+/// Discrete Laplacian of `scene` at `pos` with sample spacing `h`, which approximates the mean
+/// curvature of the surface there: positive on convex bumps and edges, negative in concave
+/// creases and close to zero on flat surfaces.
+pub fn estimate_curvature(scene: &impl Fn(Vector3) -> f64, pos: Vector3, h: f64) -> f64 {
+	let axes = [Vector3::new(h, 0.0, 0.0), Vector3::new(0.0, h, 0.0), Vector3::new(0.0, 0.0, h)];
+	let neighbors: f64 = axes.iter().map(|&d| scene(pos + d) + scene(pos - d)).sum();
+
+	(neighbors - 6.0 * scene(pos)) / (h * h)
+}
+
+/// Sphere traces `ray` through `scene`. Every step is scaled by `step_scale`: 1.0 is exact,
+/// larger values finish sooner but can step through thin features, smaller values are
+/// slower but safer for fields that overestimate the distance.
+pub fn march(ray: &Ray, scene: &impl Fn(Vector3) -> f64, step_scale: f64) -> March {
+	march_adaptive(ray, scene, step_scale, 0)
+}
+
+/// Steps that pass closer than this to a surface without hitting it are taken as a sign of a
+/// grazing ray, see `march_adaptive`.
+pub const GRAZING_DISTANCE: f64 = 0.05;
+
+/// Like `march`, but rays that keep passing within `GRAZING_DISTANCE` of a surface, typically
+/// silhouettes and grazing angles, earn one step on top of `MAX_STEPS` for every such step,
+/// up to `extra_steps` in total. Head-on rays converge quickly and never use the extra budget.
+pub fn march_adaptive(ray: &Ray, scene: &impl Fn(Vector3) -> f64, step_scale: f64, extra_steps: usize) -> March {
+	march_observed(ray, scene, step_scale, extra_steps, |_| {})
+}
+
+/// A single step of a march, as recorded by `march_trace`.
+#[derive(Clone, Copy, Debug)]
+pub struct MarchStep {
+	pub position: Vector3,
+	/// Distance `scene` reported at `position`.
+	pub distance: f64,
+	/// Distance travelled along the ray to `position`.
+	pub depth: f64,
+}
+
+/// Marches like `march_adaptive` and records every step, for diagnosing rays that overstep,
+/// oscillate or stall. See `output::write_march_trace`.
+pub fn march_trace(ray: &Ray, scene: &impl Fn(Vector3) -> f64, step_scale: f64, extra_steps: usize) -> Vec<MarchStep> {
+	let mut steps = Vec::new();
+	march_observed(ray, scene, step_scale, extra_steps, |step| steps.push(step));
+	steps
+}
+
+/// The trace of the primary ray through `pixel`, marched with `params` like `render_into` does.
+pub fn pixel_march_trace(camera: &Camera, size: (usize, usize), pixel: (usize, usize), scene: &impl Fn(Vector3) -> f64, params: &RenderParams) -> Vec<MarchStep> {
+	march_trace(&primary_ray(camera, size, pixel, params), scene, params.step_scale, params.extra_steps)
+}
+
+fn march_observed(ray: &Ray, scene: &impl Fn(Vector3) -> f64, step_scale: f64, extra_steps: usize, mut on_step: impl FnMut(MarchStep)) -> March {
+	let mut depth = EPSILON;
+	let mut budget = MAX_STEPS;
+	let mut step = 0;
+
+	while step < budget {
+		let frag_pos = ray.origin + ray.direction * depth;
+		let dist = scene(frag_pos);
+		on_step(MarchStep { position: frag_pos, distance: dist, depth });
+		step += 1;
+
+		if dist < EPSILON {
+			// were inside the surface
+			return March::Hit(HitInfo {
+				position: frag_pos,
+				normal: estimate_normal(scene, frag_pos),
+				depth,
+				steps: step,
+			});
+		}
+
+		if dist < GRAZING_DISTANCE && budget < MAX_STEPS + extra_steps {
+			budget += 1;
+		}
+
+		depth += dist * step_scale;
+
+		if depth > MAX_DISTANCE {
+			return March::Miss { steps: step };
+		}
+	}
+
+	March::Miss { steps: step }
+}
+
+/// Width of the patch a pixel of an image `height` pixels tall covers at `depth` along its ray.
+pub fn pixel_footprint(camera: &Camera, height: usize, depth: f64) -> f64 {
+	depth * 2.0 * f64::tan(camera.fov / 2.0 * PI / 180.0) / height.max(1) as f64
+}
+
+/// How much of a pixel with the given `footprint` a surface `distance` away from its sample
+/// covers, a linear ramp from 1 at half a footprint inside to 0 at half a footprint outside.
+pub fn edge_coverage(distance: f64, footprint: f64) -> f64 {
+	(0.5 - distance / footprint).clamp(0.0, 1.0)
+}
+
+/// Marches a ray that missed again and finds where it passes the surface closest relative to its
+/// pixel footprint. Returns the coverage there with a hit on that closest point, `None` if the
+/// ray never comes within half a footprint.
+pub fn silhouette(ray: &Ray, scene: &impl Fn(Vector3) -> f64, camera: &Camera, height: usize, step_scale: f64) -> Option<(f64, HitInfo)> {
+	let mut depth = EPSILON;
+	let mut best: Option<(f64, f64)> = None;
+
+	for _ in 0..MAX_STEPS {
+		let dist = scene(ray.origin + ray.direction * depth);
+		let coverage = edge_coverage(dist, pixel_footprint(camera, height, depth));
+		if coverage > best.map_or(0.0, |(c, _)| c) {
+			best = Some((coverage, depth));
+		}
+
+		depth += dist * step_scale;
+		if depth > MAX_DISTANCE {
+			break;
+		}
+	}
+
+	best.map(|(coverage, depth)| {
+		let position = ray.origin + ray.direction * depth;
+		(coverage, HitInfo { position, normal: estimate_normal(scene, position), depth, steps: 0 })
+	})
+}
+
+/// Marches every `sample_stride`th pixel in both directions and returns the mean step count,
+/// a cheap estimate of how expensive a full frame of `scene` is going to be.
+pub fn estimate_avg_steps(width: usize, height: usize, camera: &Camera, scene: &impl Fn(Vector3) -> f64, sample_stride: usize) -> f64 {
+	let stride = sample_stride.max(1);
+	let mut total = 0;
+	let mut samples = 0;
+
+	for y in (0..height).step_by(stride) {
+		for x in (0..width).step_by(stride) {
+			let ray = generate_primary_ray(camera, (width, height), (x, y));
+			total += march(&ray, scene, 1.0).steps();
+			samples += 1;
+		}
+	}
+
+	if samples == 0 {
+		return 0.0;
+	}
+
+	total as f64 / samples as f64
+}
+
+/// Renders only the ambient occlusion term as a grayscale buffer, 1.0 being unoccluded.
+/// Misses are treated as unoccluded so the pass can be multiplied over a beauty render.
+pub fn render_ao(width: usize, height: usize, camera: &Camera, scene: &impl Fn(Vector3) -> f64) -> Vec<f32> {
+	let mut buffer = vec![1.0; width * height];
+
+	for y in 0..height {
+		for x in 0..width {
+			let ray = generate_primary_ray(camera, (width, height), (x, y));
+
+			if let March::Hit(hit) = march(&ray, scene, 1.0) {
+				buffer[x + y * width] = (1.0 - ambient_occlusion(scene, hit.position, hit.normal)) as f32;
+			}
+		}
+	}
+
+	buffer
+}
+
+/// Cook-Torrance microfacet BRDF with a GGX distribution, Smith geometry term and
+/// Fresnel-Schlick. All directions point away from the surface and must be normalized.
+/// Returns the reflected fraction of the incoming light, including the cosine term.
+pub fn cook_torrance(normal: Vector3, view: Vector3, light: Vector3, roughness: f64, metallic: f64, albedo: Vector3) -> Vector3 {
+	let n_dot_l = normal.dot(light);
+	let n_dot_v = normal.dot(view);
+	if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+		return Vector3::new(0.0, 0.0, 0.0);
+	}
+
+	let half = (view + light).normalize();
+	let n_dot_h = normal.dot(half).max(0.0);
+	let h_dot_v = half.dot(view).max(0.0);
+
+	let alpha = (roughness * roughness).max(0.0001);
+	let alpha2 = alpha * alpha;
+	let denom = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+	let distribution = alpha2 / (PI * denom * denom);
+
+	let k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+	let geometry = (n_dot_l / (n_dot_l * (1.0 - k) + k)) * (n_dot_v / (n_dot_v * (1.0 - k) + k));
+
+	// dielectrics reflect ~4% head-on, metals tint the reflection with their albedo
+	let f0 = albedo.map(|c| 0.04 + (c - 0.04) * metallic);
+	let fresnel = f0.map(|f| f + (1.0 - f) * (1.0 - h_dot_v).powi(5));
+
+	let specular = fresnel * (distribution * geometry / (4.0 * n_dot_l * n_dot_v));
+	let diffuse = Vector3::new(
+		(1.0 - fresnel.x) * albedo.x,
+		(1.0 - fresnel.y) * albedo.y,
+		(1.0 - fresnel.z) * albedo.z,
+	) * ((1.0 - metallic) / PI);
+
+	(diffuse + specular) * n_dot_l
+}
+
+/// Schlick's approximation of the fraction of light reflected at an interface between media
+/// with refractive indices `n1` and `n2`, for a ray at `cos_theta` to the normal.
+pub fn fresnel_schlick(cos_theta: f64, n1: f64, n2: f64) -> f64 {
+	let r0 = ((n1 - n2) / (n1 + n2)).powi(2);
+	r0 + (1.0 - r0) * (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5)
+}
+
+/// Splits a unit `incident` direction hitting glass of index `ior` into the reflected and
+/// refracted directions plus the reflectance to blend them with, `reflected * reflectance +
+/// refracted * (1 - reflectance)`. Works from either side, `normal` points out of the glass.
+/// On total internal reflection there is no refracted ray and the reflectance is 1.
+pub fn fresnel_split(incident: Vector3, normal: Vector3, ior: f64) -> (Vector3, Option<Vector3>, f64) {
+	let entering = incident.dot(normal) < 0.0;
+	let (normal, n1, n2) = if entering { (normal, 1.0, ior) } else { (-normal, ior, 1.0) };
+
+	let reflected = incident.reflect(normal);
+	let refracted = incident.refract(normal, n1 / n2);
+
+	let reflectance = match refracted {
+		// going into the denser medium the incident angle is the larger one, coming out of it
+		// it's the transmitted one
+		Some(_) if n1 <= n2 => fresnel_schlick(-incident.dot(normal), n1, n2),
+		Some(t) => fresnel_schlick(-t.dot(normal), n1, n2),
+		None => 1.0,
+	};
+
+	(reflected, refracted, reflectance)
+}
+
+/// How directly a surface faces the viewer: 1.0 head-on, falling to 0.0 where the view ray
+/// grazes the surface at a silhouette.
+pub fn facing_ratio(normal: Vector3, view: Vector3) -> f64 {
+	normal.dot(view)
+}
+
+/// Hardness `k` for `soft_shadow` that matches the penumbra of a spherical light of `radius`
+/// seen from `distance`. The penumbra factor `k * h / t` compares the angle an occluder
+/// leaves free of the light path (`h / t`) with the angle the light covers (`radius / distance`).
+pub fn light_hardness(radius: f64, distance: f64) -> f64 {
+	if radius <= 0.0 {
+		return f64::INFINITY;
+	}
+
+	distance / radius
+}
+
+/// Marches from `origin` towards the light and returns how much of the light is visible, from
+/// 0.0 (fully shadowed) to 1.0. Near misses darken the result depending on `k`, giving a
+/// penumbra, larger `k` are harder. `origin` should already be offset off the surface.
+pub fn soft_shadow(scene: &impl Fn(Vector3) -> f64, origin: Vector3, light_dir: Vector3, max_dist: f64, k: f64) -> f64 {
+	let mut visibility: f64 = 1.0;
+	let mut t = EPSILON;
+
+	for _ in 0..MAX_STEPS {
+		if t >= max_dist {
+			break;
+		}
+
+		let dist = scene(origin + light_dir * t);
+		if dist < EPSILON {
+			return 0.0;
+		}
+
+		visibility = visibility.min(k * dist / t);
+		t += dist;
+	}
+
+	visibility.clamp(0.0, 1.0)
+}
+
+/// Whether `position` lies on one of the lines at integer x or z coordinates.
+pub fn on_grid_line(position: Vector3, line_width: f64) -> bool {
+	let distance_to_line = |v: f64| (v - v.round()).abs();
+
+	distance_to_line(position.x) < line_width / 2.0 || distance_to_line(position.z) < line_width / 2.0
+}
+
+/// Shades `hit` as seen from the direction `view`, which points from the surface towards the eye.
+pub fn shade(scene: &impl Fn(Vector3) -> f64, hit: &HitInfo, view: Vector3, params: &RenderParams) -> Vector3 {
+	let frag_pos = hit.position;
+	let normal = hit.normal;
+	let material = &params.material;
+	let mut albedo = material.albedo;
+
+	if let Some(grid) = params.grid {
+		// hits stop up to EPSILON in front of the surface
+		let on_ground = (frag_pos.y - grid.height).abs() < 2.0 * EPSILON;
+		if on_ground && on_grid_line(frag_pos, grid.line_width) {
+			albedo = albedo * GRID_LINE_DARKEN;
+		}
+	}
+
+	if let Some(outline) = params.outline {
+		if facing_ratio(normal, view) < outline.threshold {
+			return outline.color;
+		}
+	}
+
+	// the debug view needs no lighting, don't march shadows only to throw them away
+	if params.shading == Shading::Normals {
+		return normal * 0.5 + Vector3::new(0.5, 0.5, 0.5);
+	}
+
+	let light = &params.light;
+	let to_light = light.position - frag_pos;
+	let light_dir = to_light.normalize();
+
+	let attenuation = 1.0 / to_light.length_squared() * light.strength;
+
+	let visibility = if params.shadows {
+		let distance = to_light.magnitude();
+		let shadow_ray = Ray::from_surface(frag_pos, normal, light_dir, params.surface_bias);
+		soft_shadow(scene, shadow_ray.origin, light_dir, distance - light.radius, light_hardness(light.radius, distance))
+	} else {
+		1.0
+	};
+
+	let reflected = match params.shading {
+		Shading::Lambert => albedo * light_dir.dot(normal).max(0.0),
+		Shading::CookTorrance => cook_torrance(normal, view, light_dir, material.roughness, material.metallic, albedo),
+		Shading::Normals => unreachable!("normals are returned before lighting"),
+	};
+
+	let ambient = if params.occlusion {
+		params.ambient * (1.0 - ambient_occlusion(scene, frag_pos, normal))
+	} else {
+		params.ambient
+	};
+
+	reflected * light.color * (attenuation * visibility) + ambient
+}
+
+/// Packs a fully covered pixel in the output color space.
+fn encode_pixel(color: Vector3, params: &RenderParams) -> u32 {
+	encode_edge(color, 1.0, params)
+}
+
+/// Like `encode_pixel` for a surface covering only `coverage` of the pixel.
+fn encode_edge(color: Vector3, coverage: f64, params: &RenderParams) -> u32 {
+	let color = match params.white_point {
+		Some(white) => color::tone_map_reinhard_white(color, white),
+		None => color,
+	};
+	U8Color::premultiplied(params.output_colorspace.encode(color), coverage).as_u32()
+}
+
+/// Composites the premultiplied pixel `src` over `dst`.
+fn over(src: u32, dst: u32) -> u32 {
+	let alpha = src >> 24;
+	let channel = |shift: u32| {
+		let (s, d) = ((src >> shift) & 0xff, (dst >> shift) & 0xff);
+		(s + d * (255 - alpha) / 255).min(255) << shift
+	};
+
+	channel(0) | channel(8) | channel(16) | channel(24)
+}
+
+/// Marches the single mirror bounce of `ray` off `hit`, the CPU counterpart of the bounce the
+/// GPU marches once `Raymarcher::set_reflectivity` is above zero. The bounce starts
+/// `params.surface_bias` off the surface and its depth is measured from there.
+pub fn reflection_bounce(scene: &impl Fn(Vector3) -> f64, ray: &Ray, hit: &HitInfo, params: &RenderParams) -> March {
+	let direction = ray.direction.reflect(hit.normal);
+	let bounce = Ray::from_surface(hit.position, hit.normal, direction, params.surface_bias);
+
+	march_adaptive(&bounce, scene, params.step_scale, params.extra_steps)
+}
+
+/// Color of a primary ray hit, honoring `RenderParams::inside_color`.
+fn hit_color(scene: &impl Fn(Vector3) -> f64, ray: &Ray, hit: &HitInfo, params: &RenderParams) -> Vector3 {
+	match params.inside_color {
+		Some(warning) if scene(ray.origin) < 0.0 => warning,
+		_ => shade(scene, hit, -ray.direction, params),
+	}
+}
+
+/// Renders to linear, unclamped colors. Misses are black.
+pub fn render_hdr(width: usize, height: usize, camera: &Camera, scene: &impl Fn(Vector3) -> f64, params: &RenderParams) -> Vec<Vector3> {
+	let mut hdr = Vec::with_capacity(width * height);
+
+	for y in 0..height {
+		for x in 0..width {
+			let ray = primary_ray(camera, (width, height), (x, y), params);
+
+			hdr.push(match march_adaptive(&ray, scene, params.step_scale, params.extra_steps) {
+				March::Hit(hit) => hit_color(scene, &ray, &hit, params),
+				March::Miss { .. } => Vector3::new(0.0, 0.0, 0.0),
+			});
+		}
+	}
+
+	hdr
+}
+
+/// Renders `sub_frames` frames of an animated `scene(pos, time)` at times evenly spread over
+/// the `shutter` interval and averages them, so fast moving geometry gets smeared along its path.
+pub fn render_motion_blur(width: usize, height: usize, camera: &Camera, scene: &impl Fn(Vector3, f64) -> f64, params: &RenderParams, shutter: Range<f64>, sub_frames: usize) -> Vec<Vector3> {
+	let sub_frames = sub_frames.max(1);
+	let mut sum = vec![Vector3::new(0.0, 0.0, 0.0); width * height];
+
+	for i in 0..sub_frames {
+		let time = shutter.start + (shutter.end - shutter.start) * i as f64 / sub_frames as f64;
+		let frame = render_hdr(width, height, camera, &|p| scene(p, time), params);
+
+		for (acc, color) in sum.iter_mut().zip(frame) {
+			*acc += color;
+		}
+	}
+
+	sum.into_iter().map(|color| color / sub_frames as f64).collect()
+}
+
+/// Distance along the primary ray to the first hit for every pixel, `f32::INFINITY` for misses.
+pub fn render_depth(width: usize, height: usize, camera: &Camera, scene: &impl Fn(Vector3) -> f64, params: &RenderParams) -> Vec<f32> {
+	march_hits(width, height, camera, scene, params).iter()
+		.map(|hit| hit.map_or(f32::INFINITY, |hit| hit.depth as f32))
+		.collect()
+}
+
+/// Marches every pixel and keeps the hits, so the frame can be relit with `reshade` without
+/// marching again as long as the scene and camera stay put.
+pub fn march_hits(width: usize, height: usize, camera: &Camera, scene: &impl Fn(Vector3) -> f64, params: &RenderParams) -> Vec<Option<HitInfo>> {
+	let mut hits = Vec::with_capacity(width * height);
+
+	for y in 0..height {
+		for x in 0..width {
+			let ray = primary_ray(camera, (width, height), (x, y), params);
+
+			hits.push(match march_adaptive(&ray, scene, params.step_scale, params.extra_steps) {
+				March::Hit(hit) => Some(hit),
+				March::Miss { .. } => None,
+			});
+		}
+	}
+
+	hits
+}
+
+/// Shades hits cached by `march_hits` with new lighting or materials from `params`. Gives the
+/// same image as `render` with the same camera, scene and params, as long as those render a
+/// single sample per pixel: the cache holds one hit per pixel, so `aa_samples` above 1 and
+/// `analytic_aa` are ignored.
+pub fn reshade(hits: &[Option<HitInfo>], width: usize, height: usize, camera: &Camera, scene: &impl Fn(Vector3) -> f64, params: &RenderParams) -> Vec<u32> {
+	assert_eq!(hits.len(), width * height, "hits do not match the render size");
+
+	hits.iter().enumerate().map(|(i, hit)| match hit {
+		Some(hit) => {
+			let ray = primary_ray(camera, (width, height), (i % width, i / width), params);
+			encode_pixel(hit_color(scene, &ray, hit, params), params)
+		}
+		None => params.clear_color,
+	}).collect()
+}
+
+pub fn render(width: usize, height: usize, camera: &Camera, scene: &(impl Fn(Vector3) -> f64 + Sync), params: &RenderParams) -> Vec<u32> {
+	render_with_stats(width, height, camera, scene, params).0
+}
+
+/// Same as `render`, but also collects `RenderStats` for profiling.
+pub fn render_with_stats(width: usize, height: usize, camera: &Camera, scene: &(impl Fn(Vector3) -> f64 + Sync), params: &RenderParams) -> (Vec<u32>, RenderStats) {
+	let mut buffer = vec![U8Color::TRANSPARENT.as_u32(); width * height];
+	let stats = render_into(&mut buffer, width, height, camera, scene, params);
+
+	(buffer, stats)
+}
+
+/// Marches and shades the single pixel `pixel` of an image of the given `size`, the per-pixel
+/// work of `render_into`.
+pub fn shade_pixel(camera: &Camera, size: (usize, usize), pixel: (usize, usize), scene: &impl Fn(Vector3) -> f64, params: &RenderParams) -> u32 {
+	trace_pixel(camera, size, pixel, scene, params, |_| {})
+}
+
+/// `shade_pixel`, handing every ray's march result to `on_ray` for `RenderStats`.
+fn trace_pixel(camera: &Camera, size: (usize, usize), (x, y): (usize, usize), scene: &impl Fn(Vector3) -> f64, params: &RenderParams, mut on_ray: impl FnMut(&March)) -> u32 {
+	let n = params.aa_samples.max(1);
+	if n == 1 {
+		let ray = primary_ray(camera, size, (x, y), params);
+		let (color, coverage) = trace_ray(&ray, camera, size.1, scene, params, &mut on_ray);
+		return over(encode_edge(color, coverage, params), params.clear_color);
+	}
+
+	// one jittered ray per cell of an n x n grid over the pixel, averaged by coverage
+	let mut rng = pixel_rng(params.seed, x, y);
+	let mut weighted = Vector3::new(0.0, 0.0, 0.0);
+	let mut total_coverage = 0.0;
+
+	for j in 0..n {
+		for i in 0..n {
+			let offset = ((i as f64 + rng.next_f64()) / n as f64, (j as f64 + rng.next_f64()) / n as f64);
+			let ray = generate_primary_ray_at(camera, size, (x, y), offset);
+			let (color, coverage) = trace_ray(&ray, camera, size.1, scene, params, &mut on_ray);
+
+			weighted += color * coverage;
+			total_coverage += coverage;
+		}
+	}
+
+	let color = if total_coverage > 0.0 { weighted / total_coverage } else { weighted };
+	over(encode_edge(color, total_coverage / (n * n) as f64, params), params.clear_color)
+}
+
+/// Color of a primary ray and how much of it the geometry covers, 1 for hits.
+fn trace_ray(ray: &Ray, camera: &Camera, height: usize, scene: &impl Fn(Vector3) -> f64, params: &RenderParams, on_ray: &mut impl FnMut(&March)) -> (Vector3, f64) {
+	let result = march_adaptive(ray, scene, params.step_scale, params.extra_steps);
+	on_ray(&result);
+
+	match result {
+		March::Hit(hit) => (hit_color(scene, ray, &hit, params), 1.0),
+		March::Miss { .. } if params.analytic_aa => match silhouette(ray, scene, camera, height, params.step_scale) {
+			Some((coverage, hit)) => (hit_color(scene, ray, &hit, params), coverage),
+			None => (Vector3::new(0.0, 0.0, 0.0), 0.0),
+		},
+		March::Miss { .. } => (Vector3::new(0.0, 0.0, 0.0), 0.0),
+	}
+}
+
+/// Renders into a caller owned `buffer`, which must hold exactly `width * height` pixels. Rows
+/// are shaded in parallel, so `scene` has to be shareable between threads.
+pub fn render_into(buffer: &mut [u32], width: usize, height: usize, camera: &Camera, scene: &(impl Fn(Vector3) -> f64 + Sync), params: &RenderParams) -> RenderStats {
+	assert_eq!(buffer.len(), width * height, "buffer does not match the render size");
+
+	// nothing of the previous frame may survive, whatever the loop below ends up writing
+	buffer.fill(params.clear_color);
+
+	let start = Instant::now();
+
+	let empty = || (RenderStats { min_steps: usize::MAX, ..RenderStats::default() }, 0);
+	let (mut stats, total_steps) = buffer.par_chunks_mut(width.max(1))
+		.enumerate()
+		.map(|(y, row)| {
+			let (mut stats, mut total_steps) = empty();
+			// counted per row, a counter shared between threads contends on every evaluation
+			let evaluations = Cell::new(0);
+			let counted_scene = |p| {
+				evaluations.set(evaluations.get() + 1);
+				scene(p)
+			};
+
+			for (x, pixel) in row.iter_mut().enumerate() {
+				*pixel = trace_pixel(camera, (width, height), (x, y), &counted_scene, params, |result| {
+					stats.rays += 1;
+					stats.min_steps = stats.min_steps.min(result.steps());
+					stats.max_steps = stats.max_steps.max(result.steps());
+					total_steps += result.steps();
+					match result {
+						March::Hit(_) => stats.hits += 1,
+						March::Miss { .. } => stats.misses += 1,
+					}
+				});
+			}
+
+			stats.sdf_evaluations = evaluations.get();
+			(stats, total_steps)
+		})
+		.reduce(empty, |(a, a_steps), (b, b_steps)| (RenderStats {
+			rays: a.rays + b.rays,
+			hits: a.hits + b.hits,
+			misses: a.misses + b.misses,
+			min_steps: a.min_steps.min(b.min_steps),
+			max_steps: a.max_steps.max(b.max_steps),
+			sdf_evaluations: a.sdf_evaluations + b.sdf_evaluations,
+			..a
+		}, a_steps + b_steps));
+
+	if stats.rays == 0 {
+		stats.min_steps = 0;
+	} else {
+		stats.mean_steps = total_steps as f64 / stats.rays as f64;
+	}
+	stats.elapsed = start.elapsed();
+
+	stats
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StereoMode {
+	/// Left eye in the left half, right eye in the right half of a `2 * width` wide buffer.
+	SideBySide,
+	/// Red from the left eye, green and blue from the right, for red/cyan glasses.
+	Anaglyph,
+}
+
+/// Renders the scene from two eyes `ipd` apart along the camera's right vector.
+pub fn render_stereo(width: usize, height: usize, camera: &Camera, scene: &(impl Fn(Vector3) -> f64 + Sync), params: &RenderParams, ipd: f64, mode: StereoMode) -> Vec<u32> {
+	let eye = |side: f64| Camera { position: camera.position + camera.right() * (side * ipd / 2.0), ..*camera };
+
+	let mut left = vec![U8Color::TRANSPARENT.as_u32(); width * height];
+	let mut right = left.clone();
+	render_into(&mut left, width, height, &eye(-1.0), scene, params);
+	render_into(&mut right, width, height, &eye(1.0), scene, params);
+
+	match mode {
+		StereoMode::SideBySide => {
+			let mut buffer = Vec::with_capacity(2 * width * height);
+			for y in 0..height {
+				buffer.extend_from_slice(&left[y * width..(y + 1) * width]);
+				buffer.extend_from_slice(&right[y * width..(y + 1) * width]);
+			}
+			buffer
+		}
+		StereoMode::Anaglyph => {
+			left.iter().zip(&right).map(|(&l, &r)| {
+				let alpha = (l >> 24).max(r >> 24) << 24;
+				alpha | (l & 0x00ff0000) | (r & 0x0000ffff)
+			}).collect()
+		}
+	}
+}
+
+/// A horizontal mirror at `height`, reflected with planar reflections instead of extra rays.
+#[derive(Clone, Copy, Debug)]
+pub struct MirrorFloor {
+	pub height: f64,
+	/// How much of the reflection is mixed into the floor color, 0.0 to 1.0.
+	pub reflectivity: f64,
+}
+
+/// The camera for the mirror pass, `camera` reflected across the plane `y = plane_height`.
+/// A reflection can't be expressed as a camera, so this one sees the reflection upside down:
+/// the reflected ray of pixel `(x, y)` is its ray through pixel `(x, height - 1 - y)`.
+pub fn mirror_camera(camera: &Camera, plane_height: f64) -> Camera {
+	let mirror = |v: Vector3| Vector3::new(v.x, -v.y, v.z);
+	let mut position = camera.position;
+	position.y = 2.0 * plane_height - position.y;
+
+	Camera { position, forward: mirror(camera.forward), up: -mirror(camera.up), ..*camera }
+}
+
+/// Two pass render of a scene standing on a flat mirror. The first pass renders everything
+/// above the floor from `mirror_camera`, the second renders the scene normally and mixes the
+/// vertically flipped first pass into pixels that hit the floor. A ray hitting pixel `(x, y)`
+/// of the floor continues as the mirror camera's ray through pixel `(x, height - 1 - y)`.
+pub fn render_mirror_floor(width: usize, height: usize, camera: &Camera, scene: &impl Fn(Vector3) -> f64, params: &RenderParams, floor: &MirrorFloor) -> Vec<u32> {
+	let above_floor = |p: Vector3| scene(p).max(floor.height - p.y);
+	let reflection = render_hdr(width, height, &mirror_camera(camera, floor.height), &above_floor, params);
+
+	let mut buffer = Vec::with_capacity(width * height);
+
+	for y in 0..height {
+		for x in 0..width {
+			let ray = primary_ray(camera, (width, height), (x, y), params);
+
+			buffer.push(match march_adaptive(&ray, scene, params.step_scale, params.extra_steps) {
+				March::Hit(hit) => {
+					let mut color = hit_color(scene, &ray, &hit, params);
+
+					let on_floor = hit.normal.y > 0.99 && (hit.position.y - floor.height).abs() < params.surface_bias;
+					if on_floor {
+						let mirrored = reflection[x + (height - 1 - y) * width];
+						color = color.lerp(mirrored, floor.reflectivity);
+					}
+
+					encode_pixel(color, params)
+				}
+				March::Miss { .. } => params.clear_color,
+			});
+		}
+	}
+
+	buffer
+}
+
+/// Shadow catcher pass for compositing onto a photo: the objects and an invisible ground plane
+/// at `ground_height` are marched, but only the shadow the objects cast onto the ground is
+/// written, as black with alpha `1 - visibility` towards `params.light`. Everything else,
+/// including the objects themselves, is transparent.
+pub fn render_shadow_catcher(width: usize, height: usize, camera: &Camera, scene: &impl Fn(Vector3) -> f64, params: &RenderParams, ground_height: f64) -> Vec<u32> {
+	let with_ground = |p: Vector3| scene(p).min(p.y - ground_height);
+	let up = Vector3::new(0.0, 1.0, 0.0);
+	let light = &params.light;
+
+	let mut buffer = Vec::with_capacity(width * height);
+
+	for y in 0..height {
+		for x in 0..width {
+			let ray = primary_ray(camera, (width, height), (x, y), params);
+
+			let pixel = match march_adaptive(&ray, &with_ground, params.step_scale, params.extra_steps) {
+				March::Hit(hit) if hit.position.y - ground_height < scene(hit.position) => {
+					let to_light = light.position - hit.position;
+					let distance = to_light.magnitude();
+					let light_dir = to_light.normalize();
+
+					let origin = Ray::from_surface(hit.position, up, light_dir, params.surface_bias).origin;
+					let visibility = soft_shadow(scene, origin, light_dir, distance - light.radius, light_hardness(light.radius, distance));
+
+					U8Color::premultiplied(Vector3::new(0.0, 0.0, 0.0), 1.0 - visibility)
+				}
+				_ => U8Color::TRANSPARENT,
+			};
+			buffer.push(pixel.as_u32());
+		}
+	}
+
+	buffer
+}
+
+/// A backend that turns a camera into a finished frame.
+pub trait Renderer {
+	fn render(&mut self, camera: &Camera, width: usize, height: usize) -> Vec<u32>;
+}
+
+pub struct CpuRenderer<S> {
+	pub scene: S,
+	pub params: RenderParams,
+}
+
+impl<S: Fn(Vector3) -> f64> CpuRenderer<S> {
+	pub fn new(scene: S, params: RenderParams) -> Self {
+		CpuRenderer { scene, params }
+	}
+}
+
+impl<S: Fn(Vector3) -> f64 + Sync> Renderer for CpuRenderer<S> {
+	fn render(&mut self, camera: &Camera, width: usize, height: usize) -> Vec<u32> {
+		render(width, height, camera, &self.scene, &self.params)
+	}
+}
+
+/// CPU copy of the scene baked into the GPU shader, for validating the GPU path against the CPU
+/// one and for shading what the GPU hit. Has to change together with `scene()` in the shader.
+pub fn gpu_equivalent_scene(p: Vector3) -> f64 {
+	let mirror = sdf::translate(sdf::cuboid(Vector3::new(4.0, 0.1, 4.0)), Vector3::new(0.0, -2.0, 0.0));
+	sdf::min(sdf::sphere(1.5), mirror)(p)
+}
+
+/// The GPU marches the scene baked into its shader, only the shading happens on the CPU with
+/// `params`. That includes the reflection: the GPU marches the bounce, which is shaded here and
+/// blended in by `Raymarcher::reflectivity`.
+#[cfg(feature = "gpu")]
+pub struct GpuRenderer {
+	pub raymarcher: raymarcher_vulkan::Raymarcher,
+	pub params: RenderParams,
+}
+
+#[cfg(feature = "gpu")]
+impl GpuRenderer {
+	pub fn new(raymarcher: raymarcher_vulkan::Raymarcher, params: RenderParams) -> Self {
+		GpuRenderer { raymarcher, params }
+	}
+}
+
+#[cfg(feature = "gpu")]
+impl Renderer for GpuRenderer {
+	fn render(&mut self, camera: &Camera, width: usize, height: usize) -> Vec<u32> {
+		use raymarcher_vulkan::MarchInstruction;
+
+		let to_f32 = |v: Vector3| [v.x as f32, v.y as f32, v.z as f32];
+		let mut rays = Vec::with_capacity(width * height);
+		let mut inputs = Vec::with_capacity(width * height);
+
+		for y in 0..height {
+			for x in 0..width {
+				let ray = generate_primary_ray(camera, (width, height), (x, y));
+				inputs.push(MarchInstruction {
+					origin: to_f32(ray.origin),
+					direction: to_f32(ray.direction),
+				});
+				rays.push(ray);
+			}
+		}
+
+		let params = &self.params;
+		let reflectivity = self.raymarcher.reflectivity() as f64;
+		let to_f64 = |v: [f32; 3]| Vector3::new(v[0] as f64, v[1] as f64, v[2] as f64);
+
+		self.raymarcher.dispatch(&inputs).iter().zip(&rays).map(|(result, ray)| {
+			let depth = result.distance as f64;
+			if depth > MAX_DISTANCE {
+				return U8Color::TRANSPARENT.as_u32();
+			}
+
+			let hit = HitInfo {
+				position: to_f64(result.position),
+				normal: to_f64(result.normal),
+				depth,
+				steps: result.steps as usize,
+			};
+			let mut color = shade(&gpu_equivalent_scene, &hit, -ray.direction, params);
+
+			if let Some(reflection) = result.reflection {
+				// a bounce into the void reflects the black background
+				let mut reflected_color = Vector3::new(0.0, 0.0, 0.0);
+
+				if (reflection.distance as f64) <= MAX_DISTANCE {
+					let bounce = HitInfo {
+						position: to_f64(reflection.position),
+						normal: to_f64(reflection.normal),
+						depth: reflection.distance as f64,
+						steps: 0,
+					};
+					reflected_color = shade(&gpu_equivalent_scene, &bounce, -ray.direction.reflect(hit.normal), params);
+				}
+				color = color.lerp(reflected_color, reflectivity);
+			}
+
+			encode_pixel(color, params)
+		}).collect()
+	}
+}
+
+/// Frames the camera has to stay still before rendering at full resolution again.
+pub const SETTLE_FRAMES: usize = 3;
+/// Resolution divisor used while the camera moves.
+pub const MOTION_DOWNSCALE: usize = 4;
+
+pub fn camera_moved(previous: &Camera, current: &Camera) -> bool {
+	(current.position - previous.position).magnitude() > EPSILON
+		|| (current.forward - previous.forward).magnitude() > EPSILON
+		|| (current.up - previous.up).magnitude() > EPSILON
+		|| (current.fov - previous.fov).abs() > EPSILON
+}
+
+/// Drops the resolution while the camera moves to keep the interactive loop responsive.
+#[derive(Clone, Debug, Default)]
+pub struct AdaptiveResolution {
+	previous: Option<Camera>,
+	still_frames: usize,
+}
+
+impl AdaptiveResolution {
+	/// Call once per frame, returns the divisor to apply to the frame's resolution.
+	pub fn update(&mut self, camera: &Camera) -> usize {
+		match self.previous {
+			Some(previous) if camera_moved(&previous, camera) => self.still_frames = 0,
+			Some(_) => self.still_frames += 1,
+			None => self.still_frames = SETTLE_FRAMES,
+		}
+		self.previous = Some(*camera);
+
+		if self.still_frames >= SETTLE_FRAMES { 1 } else { MOTION_DOWNSCALE }
+	}
+}
+
+/// Renders at `1 / divisor` of the resolution and scales the result up to fill `buffer`.
+pub fn render_scaled_into(buffer: &mut [u32], width: usize, height: usize, divisor: usize, camera: &Camera, scene: &(impl Fn(Vector3) -> f64 + Sync), params: &RenderParams) -> RenderStats {
+	if divisor <= 1 {
+		return render_into(buffer, width, height, camera, scene, params);
+	}
+
+	assert_eq!(buffer.len(), width * height, "buffer does not match the render size");
+
+	let (small_width, small_height) = ((width / divisor).max(1), (height / divisor).max(1));
+	let (small, stats) = render_with_stats(small_width, small_height, camera, scene, params);
+
+	for y in 0..height {
+		let sy = (y * small_height / height).min(small_height - 1);
+		for x in 0..width {
+			let sx = (x * small_width / width).min(small_width - 1);
+			buffer[x + y * width] = small[sx + sy * small_width];
+		}
+	}
+
+	stats
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn fresnel_at_normal_incidence() {
+		let (n1, n2): (f64, f64) = (1.0, 1.5);
+		let expected = ((n1 - n2) / (n1 + n2)).powi(2);
+
+		assert!((fresnel_schlick(1.0, n1, n2) - expected).abs() < 1e-12);
+		assert!((fresnel_schlick(1.0, n1, n2) - 0.04).abs() < 1e-12);
+		assert!((fresnel_schlick(0.0, n1, n2) - 1.0).abs() < 1e-12);
+	}
+
+	/// Sum of the squared alpha differences between horizontal neighbors, large for hard edges.
+	fn edge_contrast(buffer: &[u32], width: usize) -> f64 {
+		buffer.chunks(width)
+			.flat_map(|row| row.windows(2))
+			.map(|pair| ((pair[0] >> 24) as f64 - (pair[1] >> 24) as f64).powi(2))
+			.sum()
+	}
+
+	#[test]
+	fn supersampling_softens_edges() {
+		let scene = sdf::sphere(1.5);
+		let single = render(32, 32, &Camera::default(), &scene, &RenderParams::default());
+		let supersampled = render(32, 32, &Camera::default(), &scene, &RenderParams { aa_samples: 2, ..RenderParams::default() });
+
+		assert!(edge_contrast(&supersampled, 32) < edge_contrast(&single, 32));
+	}
+
+	#[test]
+	fn full_frames_cost_more_steps() {
+		let camera = Camera::default();
+		let filled = estimate_avg_steps(32, 32, &camera, &sdf::sphere(4.0), 2);
+		// a small sphere well off to the side, every ray escapes after a few large steps
+		let empty = estimate_avg_steps(32, 32, &camera, &sdf::translate(sdf::sphere(0.5), Vector3::new(0.0, 20.0, 0.0)), 2);
+
+		assert!(filled > empty, "filled {} vs empty {}", filled, empty);
+	}
+
+	#[test]
+	fn crevices_are_darker_in_the_ao_pass() {
+		// a ball resting on the floor, seen from slightly above
+		let scene = sdf::min(sdf::sphere(1.0), sdf::plane(Vector3::new(0.0, 1.0, 0.0), 1.0));
+		let camera = Camera::look_at(Vector3::new(0.0, 1.0, -6.0), Vector3::new(0.0, -0.5, 0.0), 40.0);
+		let (width, height) = (32, 32);
+		let ao = render_ao(width, height, &camera, &scene);
+
+		let column: Vec<f32> = (0..height).map(|y| ao[width / 2 + y * width]).collect();
+		let crevice = column.iter().cloned().fold(f32::INFINITY, f32::min);
+
+		// the middle of the ball and the floor in front of it are open
+		assert!(crevice < column[height / 2] - 0.5);
+		assert!(crevice < column[height - 1] - 0.5);
+	}
+
+	#[test]
+	fn stats_count_every_primary_ray() {
+		let scene = sdf::sphere(1.5);
+		let (_, stats) = render_with_stats(16, 8, &Camera::default(), &scene, &RenderParams::default());
+		assert_eq!(stats.rays, 16 * 8);
+		assert_eq!(stats.hits + stats.misses, stats.rays);
+
+		let params = RenderParams { aa_samples: 3, ..RenderParams::default() };
+		let (_, stats) = render_with_stats(16, 8, &Camera::default(), &scene, &params);
+		assert_eq!(stats.rays, 16 * 8 * 3 * 3);
+	}
+
+	#[test]
+	fn misses_are_transparent_and_hits_opaque() {
+		let buffer = render(16, 16, &Camera::default(), &sdf::sphere(1.5), &RenderParams::default());
+
+		assert_eq!(buffer[0], 0, "a corner pixel misses the sphere");
+		assert_eq!(buffer[8 + 8 * 16] >> 24, 255, "the center pixel hits it");
+	}
+
+	#[test]
+	fn render_into_matches_render() {
+		let scene = sdf::sphere(1.5);
+		let params = RenderParams::default();
+		let mut buffer = vec![0; 24 * 16];
+
+		render_into(&mut buffer, 24, 16, &Camera::default(), &scene, &params);
+
+		assert_eq!(buffer, render(24, 16, &Camera::default(), &scene, &params));
+	}
+
+	#[test]
+	fn rougher_surfaces_spread_the_highlight() {
+		let normal = Vector3::new(0.0, 1.0, 0.0);
+		let view = Vector3::new(-1.0, 1.0, 0.0).normalize();
+		let mirror = Vector3::new(1.0, 1.0, 0.0).normalize();
+		let off_peak = Vector3::new(1.0, 2.0, 0.0).normalize();
+		// black albedo leaves only the specular lobe
+		let black = Vector3::new(0.0, 0.0, 0.0);
+		let specular = |light, roughness| cook_torrance(normal, view, light, roughness, 0.0, black).x;
+
+		let (smooth_peak, rough_peak) = (specular(mirror, 0.2), specular(mirror, 0.6));
+		assert!(rough_peak < smooth_peak);
+		assert!(specular(off_peak, 0.6) / rough_peak > specular(off_peak, 0.2) / smooth_peak);
+	}
+
+	/// A floor at y = -1 meeting a wall at x = 1.
+	fn corner() -> impl Fn(Vector3) -> f64 {
+		sdf::min(sdf::plane(Vector3::new(0.0, 1.0, 0.0), 1.0), sdf::plane(Vector3::new(-1.0, 0.0, 0.0), 1.0))
+	}
+
+	#[test]
+	fn cheap_ao_darkens_concavities() {
+		let up = Vector3::new(0.0, 1.0, 0.0);
+		let in_corner = cheap_ao(&corner(), Vector3::new(0.9, -1.0, 0.0), up, 0.5);
+		let on_floor = cheap_ao(&corner(), Vector3::new(-5.0, -1.0, 0.0), up, 0.5);
+
+		assert!(in_corner < on_floor);
+		assert!((on_floor - 1.0).abs() < 1e-9);
+	}
+
+	#[test]
+	fn unlit_surfaces_show_the_ambient_color() {
+		let params = RenderParams { ambient: Vector3::new(0.0, 0.0, 0.5), ..RenderParams::default() };
+		// the back of the sphere faces away from the light in front of it
+		let hit = HitInfo { position: Vector3::new(0.0, 0.0, 1.5), normal: Vector3::new(0.0, 0.0, 1.0), depth: 1.0, steps: 1 };
+
+		let color = shade(&sdf::sphere(1.5), &hit, Vector3::new(0.0, 0.0, 1.0), &params);
+
+		assert_eq!((color.x, color.y), (0.0, 0.0));
+		assert!(color.z > 0.25, "{:?}", color);
+	}
+
+	#[test]
+	fn stereo_eyes_see_different_images() {
+		// off-center so the two viewpoints can't see mirror images of the same thing
+		let scene = sdf::min(
+			sdf::sphere(1.0),
+			sdf::translate(sdf::sphere(0.5), Vector3::new(1.5, 0.5, -1.0)),
+		);
+		let (width, height) = (16, 16);
+		let buffer = render_stereo(width, height, &Camera::default(), &scene, &RenderParams::default(), 0.5, StereoMode::SideBySide);
+		assert_eq!(buffer.len(), 2 * width * height);
+
+		let half = |offset: usize| -> Vec<u32> {
+			buffer.chunks(2 * width).flat_map(|row| row[offset..offset + width].to_vec()).collect()
+		};
+		assert_ne!(half(0), half(width));
+	}
+
+	#[test]
+	fn larger_step_scales_reach_far_hits_sooner() {
+		// a wall far ahead, the floor running along the ray keeps the steps short
+		let scene = sdf::min(sdf::plane(Vector3::new(0.0, 0.0, -1.0), 60.0), sdf::plane(Vector3::new(0.0, 1.0, 0.0), 2.0));
+		let ray = Ray::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0));
+
+		let (exact, scaled) = (march(&ray, &scene, 1.0), march(&ray, &scene, 1.5));
+		assert!(matches!(exact, March::Hit(_)) && matches!(scaled, March::Hit(_)));
+		assert!(scaled.steps() < exact.steps(), "{} vs {}", scaled.steps(), exact.steps());
+	}
+
+	#[test]
+	fn backends_render_the_requested_size() {
+		let mut backends: Vec<Box<dyn Renderer>> = vec![Box::new(CpuRenderer::new(gpu_equivalent_scene, RenderParams::default()))];
+		// the GPU backend only takes part where there is a device to run on
+		#[cfg(feature = "gpu")]
+		{
+			if !raymarcher_vulkan::list_devices().is_empty() {
+				backends.push(Box::new(GpuRenderer::new(raymarcher_vulkan::Raymarcher::new(), RenderParams::default())));
+			}
+		}
+
+		for backend in backends.iter_mut() {
+			assert_eq!(backend.render(&Camera::default(), 20, 10).len(), 20 * 10);
+		}
+	}
+
+	#[test]
+	fn facing_ratio_is_one_head_on_and_zero_at_grazing() {
+		let normal = Vector3::new(0.0, 0.0, -1.0);
+
+		assert!((facing_ratio(normal, Vector3::new(0.0, 0.0, -1.0)) - 1.0).abs() < 1e-9);
+		assert!(facing_ratio(normal, Vector3::new(1.0, 0.0, 0.0)).abs() < 1e-9);
+
+		let oblique = facing_ratio(normal, Vector3::new(1.0, 0.0, -1.0).normalize());
+		assert!(oblique > 0.0 && oblique < 1.0);
+	}
+
+	#[test]
+	fn inside_color_paints_rays_starting_inside_geometry() {
+		let warning = Vector3::new(1.0, 0.0, 1.0);
+		let params = RenderParams { inside_color: Some(warning), ..RenderParams::default() };
+		let is_warning = |c: &Vector3| (*c - warning).magnitude() < 1e-9;
+		let inside = Camera::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.0, 1.0, 0.0), 64.0);
+
+		let hdr = render_hdr(4, 4, &inside, &sdf::sphere(1.0), &params);
+		assert!(hdr.iter().all(is_warning));
+
+		let hdr = render_hdr(4, 4, &Camera::default(), &sdf::sphere(1.0), &params);
+		assert!(!hdr.iter().any(is_warning));
+	}
+
+	#[test]
+	fn grid_lines_sit_on_integer_coordinates() {
+		assert!(on_grid_line(Vector3::new(2.0, 0.0, 0.4), 0.05));
+		assert!(on_grid_line(Vector3::new(0.3, 5.0, -1.01), 0.05));
+		assert!(!on_grid_line(Vector3::new(0.5, 0.0, 0.5), 0.05));
+	}
+
+	#[test]
+	fn resolution_drops_while_moving_and_recovers_when_still() {
+		let mut resolution = AdaptiveResolution::default();
+		let mut camera = Camera::default();
+
+		assert_eq!(resolution.update(&camera), 1);
+
+		camera.position += Vector3::new(1.0, 0.0, 0.0);
+		assert_eq!(resolution.update(&camera), MOTION_DOWNSCALE);
+
+		for _ in 1..SETTLE_FRAMES {
+			assert_eq!(resolution.update(&camera), MOTION_DOWNSCALE);
+		}
+		assert_eq!(resolution.update(&camera), 1);
+	}
+
+	#[test]
+	fn larger_lights_cast_softer_penumbras() {
+		let occluder = sdf::translate(sdf::sphere(0.5), Vector3::new(0.0, 2.0, 0.0));
+		// just outside the hard shadow, the ray towards the light passes 0.2 from the occluder
+		let origin = Vector3::new(0.7, 0.0, 0.0);
+		let up = Vector3::new(0.0, 1.0, 0.0);
+		let visibility = |radius| soft_shadow(&occluder, origin, up, 10.0, light_hardness(radius, 10.0));
+
+		let (small, large) = (visibility(0.1), visibility(2.0));
+		assert!((small - 1.0).abs() < 1e-9, "{}", small);
+		assert!(large < small && large > 0.0, "{}", large);
+	}
+
+	#[test]
+	fn motion_blur_averages_the_sub_frames() {
+		let moving = |p: Vector3, time: f64| sdf::sphere(1.0)(p - Vector3::new(time * 4.0, 0.0, 0.0));
+		let params = RenderParams::default();
+		let camera = Camera::default();
+
+		let blurred = render_motion_blur(8, 8, &camera, &moving, &params, 0.0..1.0, 2);
+		let start = render_hdr(8, 8, &camera, &|p| moving(p, 0.0), &params);
+		let middle = render_hdr(8, 8, &camera, &|p| moving(p, 0.5), &params);
+
+		assert!(start.iter().zip(&middle).any(|(a, b)| (*a - *b).magnitude() > 0.1), "the sphere didn't move");
+		for ((blur, a), b) in blurred.iter().zip(&start).zip(&middle) {
+			assert!((*blur - (*a + *b) / 2.0).magnitude() < 1e-9);
+		}
+	}
+
+	#[test]
+	fn mirror_camera_sees_the_reflected_rays() {
+		let camera = Camera::look_at(Vector3::new(1.0, 3.0, -10.0), Vector3::new(0.0, 0.0, 0.0), 64.0);
+		let mirrored = mirror_camera(&camera, 1.0);
+
+		assert!((mirrored.position - Vector3::new(1.0, -1.0, -10.0)).magnitude() < 1e-9);
+
+		let (width, height) = (8, 6);
+		for (x, y) in [(0, 0), (3, 2), (7, 5)] {
+			let ray = generate_primary_ray(&camera, (width, height), (x, y));
+			let reflected = generate_primary_ray(&mirrored, (width, height), (x, height - 1 - y));
+			let expected = Vector3::new(ray.direction.x, -ray.direction.y, ray.direction.z);
+
+			assert!((reflected.direction - expected).magnitude() < 1e-9);
+		}
+	}
+
+	#[test]
+	fn seeds_make_jittered_renders_reproducible() {
+		let scene = sdf::sphere(1.5);
+		let seeded = |seed| render(16, 16, &Camera::default(), &scene, &RenderParams { aa_samples: 2, seed, ..RenderParams::default() });
+
+		assert_eq!(seeded(3), seeded(3));
+		assert_ne!(seeded(3), seeded(4));
+	}
+
+	#[test]
+	fn shadow_catcher_only_keeps_the_shadow() {
+		let ball = sdf::translate(sdf::sphere(1.0), Vector3::new(0.0, 1.0, 0.0));
+		let camera = Camera::look_at(Vector3::new(0.0, 8.0, -8.0), Vector3::new(0.0, 0.0, 0.0), 64.0);
+		let params = RenderParams {
+			light: Light { position: Vector3::new(10.0, 10.0, 0.0), ..Light::default() },
+			..RenderParams::default()
+		};
+
+		let (width, height) = (32, 32);
+		let buffer = render_shadow_catcher(width, height, &camera, &ball, &params, 0.0);
+		let alpha = |x: usize, y: usize| buffer[x + y * width] >> 24;
+
+		// the ball itself and the lit ground in front of it are transparent
+		assert_eq!(alpha(width / 2, height / 2), 0);
+		assert_eq!(alpha(width / 2, height - 1), 0);
+		assert!(buffer.iter().any(|&pixel| pixel >> 24 == 255 && pixel & 0xff_ffff == 0));
+	}
+
+	#[test]
+	fn grazing_rays_get_extra_steps() {
+		let floor = |p: Vector3| p.y + 1.0;
+		let head_on = Ray::new(Vector3::new(0.0, 4.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+		// skims the floor, a hair above it
+		let grazing = Ray::new(Vector3::new(0.0, -0.99, 0.0), Vector3::new(0.0, 0.0, 1.0));
+
+		let head_on = march_adaptive(&head_on, &floor, 1.0, 20).steps();
+		let grazing = march_adaptive(&grazing, &floor, 1.0, 20).steps();
+
+		assert!(head_on < MAX_STEPS, "{}", head_on);
+		assert_eq!(grazing, MAX_STEPS + 20);
+	}
+
+	#[test]
+	fn reshading_matches_a_full_render() {
+		let scene = sdf::sphere(1.5);
+		let camera = Camera::default();
+		let hits = march_hits(24, 16, &camera, &scene, &RenderParams::default());
+
+		let relit = RenderParams {
+			light: Light { position: Vector3::new(-5.0, 2.0, -4.0), ..Light::default() },
+			shadows: true,
+			..RenderParams::default()
+		};
+
+		assert_eq!(reshade(&hits, 24, 16, &camera, &scene, &relit), render(24, 16, &camera, &scene, &relit));
+	}
+
+	#[test]
+	fn curvature_is_positive_on_spheres_and_flat_on_planes() {
+		let sphere = sdf::sphere(1.0);
+		let floor = |p: Vector3| p.y;
+
+		// the Laplacian of a sphere's distance is 2 / r
+		let on_sphere = estimate_curvature(&sphere, Vector3::new(0.0, 1.0, 0.0), 0.01);
+		assert!((on_sphere - 2.0).abs() < 0.01, "{}", on_sphere);
+		assert!(estimate_curvature(&floor, Vector3::new(0.3, 0.0, -2.0), 0.01).abs() < 1e-6);
+	}
+
+	#[test]
+	fn reflections_bounce_off_the_floor_into_the_ceiling() {
+		let scene = sdf::min(sdf::plane(Vector3::new(0.0, 1.0, 0.0), 1.0), sdf::translate(sdf::sphere(1.0), Vector3::new(0.0, 3.0, 0.0)));
+		let params = RenderParams::default();
+		let ray = Ray::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+
+		let hit = match march(&ray, &scene, 1.0) {
+			March::Hit(hit) => hit,
+			March::Miss { .. } => panic!("missed the floor"),
+		};
+		match reflection_bounce(&scene, &ray, &hit, &params) {
+			March::Hit(bounce) => assert!((bounce.position.y - 2.0).abs() < 0.01 && (bounce.depth - 3.0).abs() < 0.01),
+			March::Miss { .. } => panic!("the bounce missed the sphere"),
+		}
+	}
+
+	#[cfg(feature = "gpu")]
+	#[test]
+	fn gpu_reflections_match_the_cpu_bounce() {
+		use raymarcher_vulkan::{MarchInstruction, Raymarcher};
+
+		if raymarcher_vulkan::list_devices().is_empty() {
+			return;
+		}
+		let mut gpu = Raymarcher::new();
+		gpu.set_reflectivity(1.0);
+
+		let params = RenderParams::default();
+		let to_f32 = |v: Vector3| [v.x as f32, v.y as f32, v.z as f32];
+		// looking down at the sphere on its mirror slab, so bounces off either land on the other
+		let camera = Camera::look_at(Vector3::new(0.0, 4.0, -8.0), Vector3::new(0.0, -1.0, 0.0), 64.0);
+		let rays: Vec<Ray> = (0..16 * 16).map(|i| generate_primary_ray(&camera, (16, 16), (i % 16, i / 16))).collect();
+		let inputs: Vec<_> = rays.iter().map(|ray| MarchInstruction { origin: to_f32(ray.origin), direction: to_f32(ray.direction) }).collect();
+
+		let mut bounce_hits = 0;
+		for (ray, result) in rays.iter().zip(gpu.dispatch(&inputs)) {
+			let hit = match march(ray, &gpu_equivalent_scene, 1.0) {
+				March::Hit(hit) => hit,
+				March::Miss { .. } => {
+					assert!(result.reflection.is_none());
+					continue;
+				}
+			};
+			let reflection = result.reflection.expect("a primary hit without a bounce");
+
+			match reflection_bounce(&gpu_equivalent_scene, ray, &hit, &params) {
+				March::Hit(bounce) => {
+					assert!((reflection.distance as f64 - bounce.depth).abs() < 0.01, "{} vs {}", reflection.distance, bounce.depth);
+					bounce_hits += 1;
+				}
+				March::Miss { .. } => assert!(reflection.distance as f64 > MAX_DISTANCE),
+			}
+		}
+		assert!(bounce_hits > 0, "no bounce hit anything");
+	}
+
+	#[test]
+	fn offsets_move_the_sample_across_an_edge() {
+		// a wall at z = 0 whose left edge runs through pixel (4, 4), between its center and a
+		// sample one tenth into the pixel
+		let t = (32.0f64).to_radians().tan();
+		let edge = 10.0 * t * 0.075;
+		let wall = sdf::translate(sdf::cuboid(Vector3::new(5.0, 5.0, 0.5)), Vector3::new(edge + 5.0, 0.0, 0.5));
+		let left_of_center = OffsetTile { offsets: [(0.1, 0.5); OFFSET_TILE_SIZE * OFFSET_TILE_SIZE] };
+
+		let centered = render(8, 8, &Camera::default(), &wall, &RenderParams::default());
+		let offset = render(8, 8, &Camera::default(), &wall, &RenderParams { pixel_offsets: Some(left_of_center), ..RenderParams::default() });
+		let background = centered[4 * 8];
+
+		assert_ne!(centered[4 + 4 * 8], background);
+		assert_eq!(offset[4 + 4 * 8], background);
+		assert_ne!(offset[5 + 4 * 8], background);
+	}
+
+	#[test]
+	fn render_into_clears_a_dirty_buffer() {
+		let clear = 0xff20_4060;
+		let params = RenderParams { clear_color: clear, ..RenderParams::default() };
+		let mut buffer = vec![0xdead_beef; 16 * 8];
+
+		render_into(&mut buffer, 16, 8, &Camera::default(), &sdf::sphere(1.5), &params);
+
+		assert!(!buffer.contains(&0xdead_beef));
+		assert_eq!(buffer[0], clear);
+		assert_eq!(buffer, render(16, 8, &Camera::default(), &sdf::sphere(1.5), &params));
+	}
+
+	#[test]
+	fn edge_coverage_ramps_across_the_footprint() {
+		assert_eq!(edge_coverage(-1.0, 2.0), 1.0);
+		assert_eq!(edge_coverage(0.0, 2.0), 0.5);
+		assert_eq!(edge_coverage(0.5, 2.0), 0.25);
+		assert_eq!(edge_coverage(1.0, 2.0), 0.0);
+	}
+
+	#[test]
+	fn near_misses_partially_cover_their_pixel() {
+		let camera = Camera::default();
+		let scene = sdf::sphere(1.5);
+		// passes the sphere with 0.3 to spare, a quarter of the footprint out there
+		let (sin, cos) = (0.18f64, (1.0 - 0.18f64 * 0.18).sqrt());
+		let near = Ray::new(camera.position, Vector3::new(sin, 0.0, cos));
+		let far = Ray::new(camera.position, Vector3::new(0.5, 0.0, 0.75f64.sqrt()));
+
+		let (coverage, _) = silhouette(&near, &scene, &camera, 10, 1.0).expect("no coverage for a near miss");
+		assert!(coverage > 0.1 && coverage < 0.5, "{}", coverage);
+		assert!(silhouette(&far, &scene, &camera, 10, 1.0).is_none());
+	}
+
+	#[test]
+	fn parallel_render_matches_shading_pixel_by_pixel() {
+		let (width, height) = (24, 16);
+		let camera = Camera::default();
+		let scene = corner();
+		let params = RenderParams { shadows: true, aa_samples: 2, ..RenderParams::default() };
+
+		let serial: Vec<u32> = (0..width * height)
+			.map(|i| shade_pixel(&camera, (width, height), (i % width, i / width), &scene, &params))
+			.collect();
+
+		assert_eq!(render(width, height, &camera, &scene, &params), serial);
+	}
+
+	#[cfg(feature = "gpu")]
+	#[test]
+	fn gpu_distances_match_the_cpu_scene() {
+		use raymarcher_vulkan::{MarchInstruction, Raymarcher};
+
+		if raymarcher_vulkan::list_devices().is_empty() {
+			return;
+		}
+		let gpu = Raymarcher::new();
+
+		// parallel rays through a grid covering the sphere and some empty space around it
+		let rays: Vec<Ray> = (0..16 * 16).map(|i| {
+			let (x, y) = ((i % 16) as f64 / 5.0 - 1.5, (i / 16) as f64 / 5.0 - 1.5);
+			Ray::new(Vector3::new(x, y, -10.0), Vector3::new(0.0, 0.0, 1.0))
+		}).collect();
+		let to_f32 = |v: Vector3| [v.x as f32, v.y as f32, v.z as f32];
+		let to_f64 = |v: [f32; 3]| Vector3::new(v[0] as f64, v[1] as f64, v[2] as f64);
+		let inputs: Vec<_> = rays.iter().map(|ray| MarchInstruction { origin: to_f32(ray.origin), direction: to_f32(ray.direction) }).collect();
+
+		for (ray, result) in rays.iter().zip(gpu.dispatch(&inputs)) {
+			match march(ray, &gpu_equivalent_scene, 1.0) {
+				March::Hit(hit) => assert!((result.distance as f64 - hit.depth).abs() < 0.01, "{:?}: {} vs {}", ray.origin, result.distance, hit.depth),
+				// rays grazing the rim may hit on one side and not the other
+				March::Miss { .. } => assert!(result.distance as f64 > MAX_DISTANCE || gpu_equivalent_scene(to_f64(result.position)).abs() < 0.01),
+			}
+		}
+	}
+
+	#[test]
+	fn creases_are_more_occluded_than_open_floor() {
+		let scene = corner();
+		let up = Vector3::new(0.0, 1.0, 0.0);
+
+		let crease = ambient_occlusion(&scene, Vector3::new(0.99, -1.0, 0.0), up);
+		let open = ambient_occlusion(&scene, Vector3::new(-5.0, -1.0, 0.0), up);
+
+		assert!(open.abs() < 1e-9, "{}", open);
+		assert!(crease > 0.1, "{}", crease);
+	}
+
+	#[test]
+	fn head_on_trace_advances_until_it_hits() {
+		let ray = Ray::new(Vector3::new(0.0, 0.0, -10.0), Vector3::new(0.0, 0.0, 1.0));
+		let trace = march_trace(&ray, &sdf::sphere(1.5), 1.0, 0);
+
+		assert!(trace.len() > 1);
+		assert!(trace.windows(2).all(|w| w[1].depth > w[0].depth));
+		let last = trace.last().unwrap();
+		assert!(last.distance < EPSILON);
+		assert!((last.depth - 8.5).abs() < 0.01);
+	}
+
+	#[test]
+	fn fresnel_split_head_on_and_at_total_internal_reflection() {
+		let n = Vector3::new(0.0, 1.0, 0.0);
+		let close = |a: Vector3, b: Vector3| (a - b).magnitude() < 1e-9;
+
+		let (reflected, refracted, reflectance) = fresnel_split(-n, n, 1.5);
+		assert!((reflectance - ((1.5f64 - 1.0) / (1.5 + 1.0)).powi(2)).abs() < 1e-9);
+		assert!(close(reflected, n));
+		assert!(close(refracted.expect("no refraction head-on"), -n));
+
+		// leaving the glass at a grazing angle
+		let (reflected, refracted, reflectance) = fresnel_split(Vector3::new(1.0, 0.1, 0.0).normalize(), n, 1.5);
+		assert!(refracted.is_none());
+		assert_eq!(reflectance, 1.0);
+		assert!(close(reflected, Vector3::new(1.0, -0.1, 0.0).normalize()));
+	}
+}
@@ -0,0 +1,134 @@
+use super::*;
+use noise::Rng;
+use sdf::{Sdf, NO_SURFACE};
+
+/// A boxed distance function, as stored in the scene tree.
+pub type Shape = Box<dyn Sdf>;
+
+/// Placement of a node relative to its parent. Scaling is uniform so distances stay exact.
+#[derive(Clone, Copy, Debug)]
+pub struct Transform {
+	pub translation: Vector3,
+	pub scale: f64,
+}
+
+impl Transform {
+	pub fn translate(translation: Vector3) -> Self {
+		Transform { translation, scale: 1.0 }
+	}
+
+	/// `child` placed inside `self`, the transform a node gets from its parent.
+	pub fn then(&self, child: &Transform) -> Transform {
+		Transform {
+			translation: self.translation + child.translation * self.scale,
+			scale: self.scale * child.scale,
+		}
+	}
+
+	/// Moves a world space point into the local space of the transform.
+	pub fn to_local(&self, p: Vector3) -> Vector3 {
+		(p - self.translation) / self.scale
+	}
+}
+
+impl Default for Transform {
+	fn default() -> Self {
+		Transform::translate(Vector3::new(0.0, 0.0, 0.0))
+	}
+}
+
+/// A node in the scene tree, positioned relative to its parent. Leaves carry a shape, groups
+/// only position their children, e.g. a hand relative to an arm relative to a body.
+pub struct Node {
+	pub transform: Transform,
+	pub shape: Option<Shape>,
+	pub children: Vec<Node>,
+}
+
+impl Node {
+	pub fn leaf(transform: Transform, shape: impl Sdf + 'static) -> Self {
+		Node { transform, shape: Some(Box::new(shape)), children: Vec::new() }
+	}
+
+	pub fn group(transform: Transform, children: Vec<Node>) -> Self {
+		Node { transform, shape: None, children }
+	}
+
+	/// Flattens the tree into a list of shapes with their world transforms.
+	pub fn build(self) -> Scene {
+		let mut scene = Scene { leaves: Vec::new() };
+		self.flatten(&Transform::default(), &mut scene);
+		scene
+	}
+
+	fn flatten(self, parent: &Transform, scene: &mut Scene) {
+		let world = parent.then(&self.transform);
+
+		if let Some(shape) = self.shape {
+			scene.leaves.push((world, shape));
+		}
+		for child in self.children {
+			child.flatten(&world, scene);
+		}
+	}
+}
+
+/// A built scene tree, evaluates as the union of all its shapes.
+pub struct Scene {
+	leaves: Vec<(Transform, Shape)>,
+}
+
+impl Scene {
+	pub fn distance(&self, p: Vector3) -> f64 {
+		self.leaves.iter()
+			.map(|(transform, shape)| shape.distance(transform.to_local(p)) * transform.scale)
+			.fold(NO_SURFACE, f64::min)
+	}
+}
+
+/// A reproducible test scene of `primitive_count` spheres and boxes scattered with `Rng`, the
+/// same count and seed always give the same scene. Meant for comparing performance at a
+/// controlled complexity.
+pub fn generate_scene(primitive_count: usize, seed: u64) -> Box<dyn Sdf> {
+	let mut rng = Rng::new(seed);
+
+	let primitives = (0..primitive_count).map(|_| {
+		let center = Vector3::new(rng.range(-5.0, 5.0), rng.range(-3.0, 3.0), rng.range(-5.0, 5.0));
+		let size = rng.range(0.2, 1.0);
+
+		let shape: Box<dyn Sdf> = if rng.next_f64() < 0.5 {
+			Box::new(sdf::Translate { sdf: sdf::Sphere { radius: size }, translation: center })
+		} else {
+			Box::new(sdf::Translate { sdf: sdf::Cuboid { half_extents: Vector3::new(size, size, size) }, translation: center })
+		};
+		shape
+	}).collect();
+
+	Box::new(sdf::UnionAll(primitives))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn leaves_inherit_every_parent_transform() {
+		let leaf = Node::leaf(Transform::default(), sdf::Sphere { radius: 1.0 });
+		let arm = Node::group(Transform::translate(Vector3::new(0.0, 1.0, 0.0)), vec![leaf]);
+		let body = Node::group(Transform { translation: Vector3::new(1.0, 0.0, 0.0), scale: 2.0 }, vec![arm]);
+
+		// a sphere of radius 2 around (1, 2, 0)
+		let scene = body.build();
+		assert!((scene.distance(Vector3::new(1.0, 2.0, 0.0)) + 2.0).abs() < 1e-9);
+		assert!((scene.distance(Vector3::new(1.0, 5.0, 0.0)) - 1.0).abs() < 1e-9);
+	}
+
+	#[test]
+	fn generated_scenes_depend_only_on_count_and_seed() {
+		let (a, b, other) = (generate_scene(20, 9), generate_scene(20, 9), generate_scene(20, 10));
+		let points: Vec<Vector3> = (0..50).map(|i| Vector3::new(i as f64 * 0.2 - 5.0, 0.3, -i as f64 * 0.1)).collect();
+
+		assert!(points.iter().all(|&p| a.distance(p) == b.distance(p)));
+		assert!(points.iter().any(|&p| a.distance(p) != other.distance(p)));
+	}
+}
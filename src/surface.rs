@@ -0,0 +1,66 @@
+use super::*;
+use noise::Rng;
+use render::estimate_normal;
+
+const PROJECTION_STEPS: usize = 8;
+
+/// Pulls `p` onto the nearest point of the zero isosurface of `scene` by repeatedly stepping
+/// the distance along the negative gradient. Converges in a few steps for exact distance
+/// fields, fields that only bound the distance may need many more or never get there, so
+/// check `scene` at the result if it matters.
+pub fn closest_surface_point(scene: &impl Fn(Vector3) -> f64, p: Vector3, iterations: usize) -> Vector3 {
+	let mut p = p;
+
+	for _ in 0..iterations {
+		p -= estimate_normal(scene, p) * scene(p);
+	}
+
+	p
+}
+
+/// Scatters up to `count` points on the zero isosurface of `scene`. Points are seeded uniformly
+/// in the box `bounds` (min and max corner) and pulled onto the surface with a few Newton steps
+/// along the gradient. Seeds that don't converge are dropped, so fewer points may come back.
+pub fn surface_points(scene: &impl Fn(Vector3) -> f64, bounds: (Vector3, Vector3), count: usize, seed: u64) -> Vec<Vector3> {
+	let (min, max) = bounds;
+	let mut rng = Rng::new(seed);
+	let mut points = Vec::with_capacity(count);
+
+	for _ in 0..count {
+		let seed = Vector3::new(rng.range(min.x, max.x), rng.range(min.y, max.y), rng.range(min.z, max.z));
+		let p = closest_surface_point(scene, seed, PROJECTION_STEPS);
+
+		if scene(p).abs() < EPSILON {
+			points.push(p);
+		}
+	}
+
+	points
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn surface_points_lie_on_the_surface() {
+		let scene = sdf::sphere(1.0);
+		let bounds = (Vector3::new(-2.0, -2.0, -2.0), Vector3::new(2.0, 2.0, 2.0));
+
+		let points = surface_points(&scene, bounds, 100, 7);
+
+		assert!(points.len() > 50, "only {} points converged", points.len());
+		for p in points {
+			assert!(scene(p).abs() < EPSILON);
+			assert!((p.magnitude() - 1.0).abs() < 1e-2);
+		}
+	}
+
+	#[test]
+	fn closest_point_converges_onto_a_sphere() {
+		let scene = sdf::sphere(1.0);
+		let p = closest_surface_point(&scene, Vector3::new(2.0, 0.0, 0.0), PROJECTION_STEPS);
+
+		assert!((p - Vector3::new(1.0, 0.0, 0.0)).magnitude() < 1e-6, "{:?}", p);
+	}
+}
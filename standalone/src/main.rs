@@ -5,7 +5,7 @@ use raymarcher_vulkan::prelude::*;
 mod math;
 use math::prelude::*;
 
-use minifb::{Key, Window, WindowOptions};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
 
 const PI: f64 = ::std::f64::consts::PI;
 
@@ -25,6 +25,11 @@ fn generate_primary_ray((width, height): (usize, usize), (x, y): (usize, usize),
 	)
 }
 
+/// Debug color of a surface with the given unit `normal`, each axis mapped from `[-1, 1]` to `[0, 1]`.
+fn normal_color(normal: Vector3) -> Vector3 {
+	normal * 0.5 + Vector3::new(0.5, 0.5, 0.5)
+}
+
 
 
 
@@ -33,6 +38,13 @@ fn main() {
 	const HEIGHT: usize = 600;
 	const MAX_STEPS: usize = 50;
 
+	if std::env::args().any(|arg| arg == "--list-devices") {
+		for device in raymarcher_vulkan::list_devices() {
+			println!("{}: {} ({:?}, compute: {})", device.index, device.name, device.device_type, device.supports_compute);
+		}
+		return;
+	}
+
 	let mut window = Window::new(
 		"Send help",
 		WIDTH,
@@ -45,6 +57,7 @@ fn main() {
 
 	let mut time = ::std::time::Instant::now();
 	let mut colors =  vec![0; WIDTH*HEIGHT];
+	let mut show_normals = false;
 
 	while window.is_open() && !window.is_key_down(Key::Escape) {
 		if time.elapsed().as_millis() < 1000 {
@@ -53,6 +66,11 @@ fn main() {
 
 		time = ::std::time::Instant::now();
 
+		// N toggles the debug view coloring each hit by its normal
+		if window.is_key_pressed(Key::N, KeyRepeat::No) {
+			show_normals = !show_normals;
+		}
+
 		let mut inputs = vec![MarchInstruction::default(); WIDTH*HEIGHT];
 
 		for y in 0..HEIGHT {
@@ -72,29 +90,32 @@ fn main() {
 		
 		for y in 0..HEIGHT {
 			for x in 0..WIDTH {
-				colors[x + y * WIDTH] = (|result: MarchResult, input: MarchInstruction| {
+				colors[x + y * WIDTH] = (|result: MarchResult, _input: MarchInstruction| {
 									
-					if result.distance < EPSILON as f32 {
+					if result.distance < MISS_DEPTH {
 
-							let frag_pos = Vector3::from_slice(input.origin) + Vector3::from_slice(input.direction) * result.distance as f64;
+							let frag_pos = Vector3::from_slice(result.position);
 							
 							let normal = Vector3::from_slice(result.normal);
+
+							if show_normals {
+								return U8Color::premultiplied(normal_color(normal), 1.0).as_u32()
+							}
 							
 							let light_pos = Vector3::new(4.0, 3.0, -6.0);
 							let light_dir = (light_pos - frag_pos).normalize();
 							let light_strength = 10.0;
 
-							let distance = (light_pos - frag_pos).magnitude();
-							let attenuation = 1.0 / (distance*distance) * light_strength;
+							let attenuation = 1.0 / (light_pos - frag_pos).length_squared() * light_strength;
 
 							let cos_theta = light_dir.dot(normal).max(0.0);
 
 							let color = Vector3::new(1.0, 0.0, 0.0) * cos_theta * attenuation + Vector3::new(0.04, 0.04, 0.04);
 
-							return U8Color::from_vec(Vector3::new(1.0, 0.0, 0.0), 255).as_u32()
+							return U8Color::premultiplied(Vector3::new(1.0, 0.0, 0.0), 1.0).as_u32()
 					}
 
-					0x00000000
+					U8Color::TRANSPARENT.as_u32()
 				})(results[x + y * WIDTH], inputs[x + y * WIDTH]);
 			}
 		}
@@ -107,3 +128,17 @@ fn main() {
 	::std::process::exit(0);
 
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn surface_facing_z_is_bluish() {
+		let color = normal_color(Vector3::new(0.0, 0.0, 1.0));
+
+		assert!((color.x - 0.5).abs() < 1e-9);
+		assert!((color.y - 0.5).abs() < 1e-9);
+		assert!((color.z - 1.0).abs() < 1e-9);
+	}
+}
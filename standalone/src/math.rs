@@ -32,6 +32,11 @@ impl Vector3 {
 		(self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
 	}
 
+	/// `magnitude()` squared, without the square root.
+	pub fn length_squared(&self) -> f64 {
+		self.x * self.x + self.y * self.y + self.z * self.z
+	}
+
 	pub fn map(&self, f: impl Fn(f64) -> f64) -> Self {
 		Vector3 {
 			x: f(self.x),
@@ -43,6 +48,20 @@ impl Vector3 {
 	pub fn dot(&self, rhs: Vector3) -> f64 {
 		self.x *  rhs.x + self.y * rhs.y + self.z * rhs.z
 	}
+
+	pub fn cross(&self, rhs: Vector3) -> Vector3 {
+		Vector3::new(
+			self.y * rhs.z - self.z * rhs.y,
+			self.z * rhs.x - self.x * rhs.z,
+			self.x * rhs.y - self.y * rhs.x,
+		)
+	}
+
+	/// Mirrors an incoming direction about `normal`, e.g. a view ray about the surface it hits.
+	/// `normal` has to be unit length, otherwise the result is scaled and skewed.
+	pub fn reflect(&self, normal: Vector3) -> Vector3 {
+		*self - normal * 2.0 * self.dot(normal)
+	}
 }
 
 #[repr(C)]
@@ -54,6 +73,8 @@ pub struct U8Color {
 }
 
 impl U8Color {
+	pub const TRANSPARENT: U8Color = U8Color { r: 0, g: 0, b: 0, a: 0 };
+
 	pub fn as_u32(self) -> u32 {
 		self.b as u32 | ((self.g as u32) << 8) | ((self.r as u32) << 16) | ((self.a as u32) << 24)
 	}
@@ -66,10 +87,18 @@ impl U8Color {
 			a: a
 		}
 	}
+
+	/// Packs `color` with premultiplied alpha, `coverage` being the fraction of the pixel
+	/// covered by geometry. Fully covered pixels get an alpha of 255, misses should use
+	/// `U8Color::TRANSPARENT`.
+	pub fn premultiplied(color: Vector3, coverage: f64) -> Self {
+		let coverage = coverage.clamp(0.0, 1.0);
+		Self::from_vec(color.map(|v| v.clamp(0.0, 1.0)) * coverage, (coverage * 255.0) as u8)
+	}
 }
 
 
-use std::ops::{Add, Div, Mul, Sub};
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
 
 
 impl Add for Vector3 {
@@ -96,6 +125,22 @@ impl Sub for Vector3 {
 	}
 }
 
+impl AddAssign for Vector3 {
+	fn add_assign(&mut self, rhs: Vector3) {
+		self.x += rhs.x;
+		self.y += rhs.y;
+		self.z += rhs.z;
+	}
+}
+
+impl SubAssign for Vector3 {
+	fn sub_assign(&mut self, rhs: Vector3) {
+		self.x -= rhs.x;
+		self.y -= rhs.y;
+		self.z -= rhs.z;
+	}
+}
+
 impl Div<f64> for Vector3 {
 	type Output = Self;
 
@@ -112,6 +157,31 @@ impl Mul<f64> for Vector3 {
 	}
 }
 
+/// Component-wise product, e.g. for tinting a surface color by a light color.
+impl Mul<Vector3> for Vector3 {
+	type Output = Self;
+
+	fn mul(self, rhs: Vector3) -> Self::Output {
+		Vector3::new(self.x * rhs.x, self.y * rhs.y, self.z * rhs.z)
+	}
+}
+
+impl Mul<Vector3> for f64 {
+	type Output = Vector3;
+
+	fn mul(self, rhs: Vector3) -> Self::Output {
+		rhs.map(|v| self * v)
+	}
+}
+
+impl Neg for Vector3 {
+	type Output = Self;
+
+	fn neg(self) -> Self::Output {
+		self.map(|v| -v)
+	}
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Ray {
 	pub origin: Vector3,
@@ -124,3 +194,20 @@ impl Ray {
 	}
 }
 
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn compound_assignment_matches_the_binary_operators() {
+		let (a, b) = (Vector3::new(1.0, 2.0, 3.0), Vector3::new(0.5, -1.0, 4.0));
+
+		let mut sum = a;
+		sum += b;
+		assert!((sum - (a + b)).magnitude() < 1e-9);
+
+		let mut difference = a;
+		difference -= b;
+		assert!((difference - (a - b)).magnitude() < 1e-9);
+	}
+}
@@ -15,25 +15,54 @@
 // GPU", or *GPGPU*. This is what this example demonstrates.
 
 use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
-use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::command_buffer::{AutoCommandBuffer, AutoCommandBufferBuilder, CommandBufferExecFuture};
 use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
-use vulkano::device::{Device, DeviceExtensions};
+use vulkano::descriptor::pipeline_layout::PipelineLayout;
+use vulkano::device::{Device, DeviceExtensions, Queue};
+use vulkano::format::Format;
+use vulkano::image::{Dimensions, StorageImage};
 use vulkano::instance::{Instance, InstanceExtensions, PhysicalDevice};
+pub use vulkano::instance::PhysicalDeviceType;
 use vulkano::pipeline::ComputePipeline;
-use vulkano::sync::GpuFuture;
+use vulkano::sync::{FenceSignalFuture, GpuFuture, NowFuture};
 use vulkano::sync;
 
+use std::collections::VecDeque;
 use std::sync::Arc;
 
 pub mod prelude {
     pub const EPSILON: f64 = 0.0005;
-    pub use super::{MarchInstruction, MarchResult};    
+    /// Distance at which the shader counts a ray as having hit, `HIT_EPSILON` in the shader.
+    pub const HIT_EPSILON: f32 = 0.001;
+    /// Depth the shader reports for rays that escaped or ran out of steps, `MISS_DEPTH` in the
+    /// shader.
+    pub const MISS_DEPTH: f32 = 10000.0;
+    /// Scene evaluations a ray may take before the shader gives up on it, `MAX_STEPS` in the
+    /// shader.
+    pub const MAX_STEPS: u32 = 50;
+    pub use super::{MarchInstruction, MarchResult, ReflectionResult};    
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct MarchResult {
+    /// Depth along the ray to the hit, `prelude::MISS_DEPTH` if there was none.
     pub distance: f32,
     pub normal: [f32; 3],
+    /// World space hit point, `origin + direction * distance`.
+    pub position: [f32; 3],
+    /// Number of scene evaluations the ray took before it hit, escaped or ran out of steps.
+    pub steps: u32,
+    /// Where the ray went after one mirror bounce off the primary hit. Only marched for
+    /// primary hits while the reflectivity is above zero, see `Raymarcher::set_reflectivity`.
+    pub reflection: Option<ReflectionResult>,
+}
+
+/// The secondary hit of a reflected ray, `distance` is measured from the primary hit.
+#[derive(Debug, Clone, Copy)]
+pub struct ReflectionResult {
+    pub distance: f32,
+    pub normal: [f32; 3],
+    pub position: [f32; 3],
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -56,38 +85,29 @@ impl From<MarchInstruction> for MarchResult {
         MarchResult {
             distance: instr.origin[0],
             normal: instr.direction,
+            position: [0.0; 3],
+            steps: 0,
+            reflection: None,
         }
     }
 }
 
 
-pub fn compute(data: &Vec<MarchInstruction>) -> Vec<MarchResult> {
-    let instance = Instance::new(None, &InstanceExtensions::none(), None).unwrap();
-
-    // Choose which physical device to use.
-    let physical = PhysicalDevice::enumerate(&instance).next().unwrap();
-
-    // The Vulkan specs guarantee that a compliant implementation must provide at least one queue
-    // that supports compute operations.
-    let queue_family = physical.queue_families().find(|&q| q.supports_compute()).unwrap();
-
-    // Now initializing the device.
-    let (device, mut queues) = Device::new(physical, physical.supported_features(),
-        &DeviceExtensions::none(), [(queue_family, 0.5)].iter().cloned()).unwrap();
-
-    // Since we can request multiple queues, the `queues` variable is in fact an iterator. In this
-    // example we use only one queue, so we just retrieve the first and only element of the
-    // iterator and throw it away.
-    let queue = queues.next().unwrap();
-
-    println!("Device initialized");
-
+/// Center and radius of a sphere enclosing the scene baked into the shader, which uses it to
+/// skip rays that can't hit anything. Has to be kept in sync with `scene()` in the shader, and
+/// so does `render::gpu_equivalent_scene` in the main crate.
+pub fn scene_bounding_sphere() -> ([f32; 3], f32) {
+    // the far corners of the mirror slab lie further out than the sphere, and a ray stops
+    // HIT_EPSILON short of them
+    let slab_corner = (4.0f32 * 4.0 + 2.1 * 2.1 + 4.0 * 4.0).sqrt();
+    ([0.0; 3], slab_corner + prelude::HIT_EPSILON)
+}
 
-    // We need to create the compute pipeline that describes our operation.
-        mod cs {
-            vulkano_shaders::shader!{
-                ty: "compute",
-                src: "
+// The compute pipeline that describes our operation.
+mod cs {
+    vulkano_shaders::shader!{
+        ty: "compute",
+        src: "
 #version 450
 
 layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
@@ -95,23 +115,40 @@ layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
 struct InputData {
 	vec3 origin;
 	vec3 dir;
+	vec3 position;
+	vec3 reflected_normal;
+	vec3 reflected_position;
 };
 
 layout(set = 0, binding = 0) buffer Data {
 	InputData data[];
 } data;
 
+layout(push_constant) uniform PushConstants {
+	vec3 bounds_center;
+	float bounds_radius;
+	float reflectivity;
+} pc;
+
 
 float sphere(vec3 p, float radius) {
     return length(p) - radius;
 }
 
+// A flat mirror slab under the sphere, for reflection bounces to land on.
+float floor_slab(vec3 p) {
+    vec3 q = abs(p - vec3(0.0, -2.0, 0.0)) - vec3(4.0, 0.1, 4.0);
+    return length(max(q, 0.0)) + min(max(q.x, max(q.y, q.z)), 0.0);
+}
+
 float scene(vec3 p) {
-    return sphere(p, 1.5);
+    return min(sphere(p, 1.5), floor_slab(p));
 }
 
 
 float EPSILON = 0.0001;
+float HIT_EPSILON = 0.001;
+float MISS_DEPTH = 10000.0;
 
 vec3 gradient(vec3 pos) {
 	return normalize(vec3(
@@ -122,86 +159,637 @@ vec3 gradient(vec3 pos) {
 }
 
 
-void main() {
+bool misses_bounds(vec3 origin, vec3 direction) {
+    vec3 closest = origin + direction * max(dot(pc.bounds_center - origin, direction), 0.0);
+    return length(closest - pc.bounds_center) > pc.bounds_radius;
+}
+
+// Marches until the ray hits or escapes, returns the depth and counts the scene evaluations.
+float march(vec3 origin, vec3 direction, out int steps) {
     int MAX_STEPS = 50;
 
+    steps = 0;
+    // Rays passing outside the scene's bounding sphere can't hit anything, don't march them.
+    if (misses_bounds(origin, direction)) { return MISS_DEPTH; }
+
+    float depth = 0.0001;
+    for (int i = 0; i < MAX_STEPS; i++) {
+        steps = i + 1;
+        float dist = scene(origin + direction * depth);
+
+        // Leave as soon as the ray is done instead of idling through the remaining steps.
+        if (dist < HIT_EPSILON) { return depth; }
+        depth += dist;
+        if (depth > MISS_DEPTH) { return MISS_DEPTH; }
+    }
+
+    // Out of steps without getting close enough is a miss, like on the CPU.
+    return MISS_DEPTH;
+}
+
+void main() {
+    // Each invocation only touches its own element, which keeps results in input order.
     uint idx = gl_GlobalInvocationID.x;
+    if (idx >= uint(data.data.length())) { return; }
 
-    
     vec3 origin = data.data[idx].origin;
     vec3 direction = data.data[idx].dir;
 
+    int steps;
+    float depth = march(origin, direction, steps);
+    vec3 position = origin + direction * depth;
+    vec3 normal = depth < MISS_DEPTH ? gradient(position) : vec3(0.0);
 
-    float depth = 0.0001;
-    for (int i = 0; i < MAX_STEPS; i++) {
-        if (depth < 0.001 || depth > 10000.0) { continue; }
-        vec3 frag_pos = origin + direction * depth;
-        
-        float dist = scene(frag_pos);
+    data.data[idx].origin.x = depth;
+    data.data[idx].origin.y = float(steps);
+    data.data[idx].dir = normal;
+    data.data[idx].position = position;
+
+    // A single mirror bounce, the CPU blends it in by the reflectivity. z holds the depth of
+    // the reflected hit, negative when there was no bounce.
+    data.data[idx].origin.z = -1.0;
+    if (pc.reflectivity > 0.0 && depth < MISS_DEPTH) {
+        vec3 reflected = reflect(direction, normal);
+        vec3 bounce_origin = position + normal * (4.0 * HIT_EPSILON);
+
+        int bounce_steps;
+        float bounce_depth = march(bounce_origin, reflected, bounce_steps);
+        vec3 bounce_position = bounce_origin + reflected * bounce_depth;
+
+        data.data[idx].origin.z = bounce_depth;
+        data.data[idx].reflected_position = bounce_position;
+        data.data[idx].reflected_normal = bounce_depth < MISS_DEPTH ? gradient(bounce_position) : vec3(0.0);
+    }
+}"
+    }
+}
 
-        depth += dist;
+// Shades the march results in place on the GPU and writes them to an image, see
+// `Raymarcher::dispatch_to_image`. Unoccluded Lambert with the CPU side's default light and
+// material (`Light::default()`, `Material::default()` and a flat 0.04 ambient), kept in sync by
+// hand. Unlike the CPU default there is no ambient occlusion.
+mod shade_cs {
+    vulkano_shaders::shader!{
+        ty: "compute",
+        src: "
+#version 450
+
+layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+struct InputData {
+	vec3 origin;
+	vec3 dir;
+	vec3 position;
+	vec3 reflected_normal;
+	vec3 reflected_position;
+};
+
+layout(set = 0, binding = 0) buffer Data {
+	InputData data[];
+} data;
+
+layout(set = 0, binding = 1, rgba32f) uniform writeonly image2D target;
+
+layout(push_constant) uniform PushConstants {
+	uint width;
+} pc;
+
+float MISS_DEPTH = 10000.0;
+
+void main() {
+    uint idx = gl_GlobalInvocationID.x;
+    if (idx >= uint(data.data.length())) { return; }
+
+    ivec2 pixel = ivec2(idx % pc.width, idx / pc.width);
+
+    // the march pass left the depth in origin.x and the normal in dir
+    float depth = data.data[idx].origin.x;
+    if (depth >= MISS_DEPTH) {
+        imageStore(target, pixel, vec4(0.0));
+        return;
     }
 
-    data.data[idx].origin.x = depth;
-    data.data[idx].dir = gradient(origin + direction * depth);
+    vec3 position = data.data[idx].position;
+    vec3 normal = data.data[idx].dir;
+
+    vec3 to_light = vec3(4.0, 3.0, -6.0) - position;
+    float attenuation = 10.0 / dot(to_light, to_light);
+    float cos_theta = max(dot(normalize(to_light), normal), 0.0);
+    vec3 color = vec3(1.0, 0.0, 0.0) * cos_theta * attenuation + vec3(0.04);
+
+    imageStore(target, pixel, vec4(color, 1.0));
 }"
+    }
+}
+
+/// A physical device as reported by the driver, see `list_devices`.
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    /// Position in the enumeration order, the device `Raymarcher::new` uses is index 0.
+    pub index: usize,
+    pub name: String,
+    pub device_type: PhysicalDeviceType,
+    pub supports_compute: bool,
+}
+
+/// Lists the physical devices without creating a logical `Device` on any of them. Empty if
+/// there is no Vulkan driver to ask.
+pub fn list_devices() -> Vec<DeviceInfo> {
+    let instance = match Instance::new(None, &InstanceExtensions::none(), None) {
+        Ok(instance) => instance,
+        Err(_) => return Vec::new(),
+    };
+
+    PhysicalDevice::enumerate(&instance).map(|physical| DeviceInfo {
+        index: physical.index(),
+        name: physical.name(),
+        device_type: physical.ty(),
+        supports_compute: physical.queue_families().any(|q| q.supports_compute()),
+    }).collect()
+}
+
+/// What a queue family supports, enough to choose families without a device at hand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueFamilyCaps {
+    pub graphics: bool,
+    pub compute: bool,
+    pub transfer: bool,
+}
+
+/// Picks the `(compute, transfer)` queue family indices from `families`. For transfers a
+/// family that does nothing else (usually a separate DMA engine) is preferred, then any other
+/// family than the compute one. `None` for transfer means the device has a single usable
+/// family and uploads have to share the compute queue. `None` overall means no compute support.
+pub fn select_queue_families(families: &[QueueFamilyCaps]) -> Option<(usize, Option<usize>)> {
+    let compute = families.iter().position(|f| f.compute)?;
+
+    // graphics and compute families implicitly support transfers as well
+    let can_transfer = |f: &QueueFamilyCaps| f.transfer || f.graphics || f.compute;
+
+    let dedicated = families.iter().position(|f| f.transfer && !f.graphics && !f.compute);
+    let transfer = dedicated.or_else(|| {
+        families.iter().enumerate()
+            .position(|(i, f)| i != compute && can_transfer(f))
+    });
+
+    Some((compute, transfer))
+}
+
+/// Default priority of the compute queue, in `[0, 1]`.
+pub const DEFAULT_QUEUE_PRIORITY: f32 = 0.5;
+
+/// Owns the Vulkan device and compute pipeline, so they can be reused across dispatches.
+pub struct Raymarcher {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    transfer_queue: Arc<Queue>,
+    reflectivity: f32,
+    pipeline: Arc<ComputePipeline<PipelineLayout<cs::Layout>>>,
+    shade_pipeline: Arc<ComputePipeline<PipelineLayout<shade_cs::Layout>>>,
+}
+
+impl Raymarcher {
+    pub fn new() -> Raymarcher {
+        Raymarcher::with_priority(DEFAULT_QUEUE_PRIORITY)
+    }
+
+    /// Like `new`, but requests the queues with the given `priority` in `[0, 1]`.
+    pub fn with_priority(priority: f32) -> Raymarcher {
+        let instance = Instance::new(None, &InstanceExtensions::none(), None).unwrap();
+
+        // Choose which physical device to use.
+        let physical = PhysicalDevice::enumerate(&instance).next().unwrap();
+
+        // The Vulkan specs guarantee that a compliant implementation must provide at least one queue
+        // that supports compute operations.
+        let families: Vec<_> = physical.queue_families().collect();
+        let caps: Vec<_> = families.iter().map(|f| QueueFamilyCaps {
+            graphics: f.supports_graphics(),
+            compute: f.supports_compute(),
+            transfer: f.explicitly_supports_transfers(),
+        }).collect();
+        let (compute_family, transfer_family) = select_queue_families(&caps)
+            .expect("the device has no queue family supporting compute, which Vulkan requires");
+
+        // Now initializing the device, with a transfer queue of its own where there is a family for it.
+        let priority = priority.clamp(0.0, 1.0);
+        let requested: Vec<_> = std::iter::once(compute_family).chain(transfer_family)
+            .map(|family| (families[family], priority))
+            .collect();
+        let (device, mut queues) = Device::new(physical, physical.supported_features(),
+            &DeviceExtensions::none(), requested).unwrap();
+
+        let queue = queues.next().unwrap();
+        let transfer_queue = queues.next().unwrap_or_else(|| queue.clone());
+
+        println!("Device initialized");
+
+        let pipeline = Arc::new({
+            let shader = cs::Shader::load(device.clone()).unwrap();
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &()).unwrap()
+        });
+        let shade_pipeline = Arc::new({
+            let shader = shade_cs::Shader::load(device.clone()).unwrap();
+            ComputePipeline::new(device.clone(), &shader.main_entry_point(), &()).unwrap()
+        });
+
+        Raymarcher { device, queue, transfer_queue, reflectivity: 0.0, pipeline, shade_pipeline }
+    }
+
+    /// The queue meant for uploads, from a dedicated transfer family if the device has one and
+    /// the compute queue otherwise. Dispatches don't stage their uploads through it yet, they
+    /// write host visible buffers the compute queue reads directly.
+    pub fn transfer_queue(&self) -> &Arc<Queue> {
+        &self.transfer_queue
+    }
+
+    pub fn reflectivity(&self) -> f32 {
+        self.reflectivity
+    }
+
+    /// How much of a single mirror bounce is blended into every hit, 0.0 (the default) skips
+    /// marching the bounce altogether. The shader only marches the bounce, shading it and
+    /// blending it in by the reflectivity is left to the caller, see `MarchResult::reflection`.
+    pub fn set_reflectivity(&mut self, reflectivity: f32) {
+        self.reflectivity = reflectivity.clamp(0.0, 1.0);
+    }
+
+    /// Marches every instruction on the GPU, `result[i]` belongs to `data[i]`. This holds however
+    /// the work is split into workgroups or batches, anything that reorders rays internally has
+    /// to restore the input order before returning.
+    pub fn dispatch(&self, data: &[MarchInstruction]) -> Vec<MarchResult> {
+        let results = InFlight::finish(self.submit(data));
+        debug_assert_eq!(results.len(), data.len(), "every instruction gets exactly one result");
+        results
+    }
+
+    /// Marches several independent batches, e.g. the frames of an animation, `results[i]`
+    /// belonging to `batches[i]`. The next batch is uploaded and submitted while the GPU still
+    /// works on the previous one, so the GPU doesn't sit idle while the CPU prepares work.
+    pub fn dispatch_batches(&self, batches: &[Vec<MarchInstruction>]) -> Vec<Vec<MarchResult>> {
+        let mut results = Vec::with_capacity(batches.len());
+        let mut in_flight = VecDeque::with_capacity(2);
+
+        for batch in batches {
+            in_flight.push_back(self.submit(batch));
+
+            if in_flight.len() > 1 {
+                results.push(InFlight::finish(in_flight.pop_front().unwrap()));
             }
         }
-    let pipeline = Arc::new({
-        let shader = cs::Shader::load(device.clone()).unwrap();
-        ComputePipeline::new(device.clone(), &shader.main_entry_point(), &()).unwrap()
-    });
 
-    // We start by creating the buffer that will store the data.
-    let data_buffer = {
-        // Iterator that produces the data.
+        results.extend(in_flight.into_iter().map(InFlight::finish));
+        results
+    }
+
+    /// Marches `data`, the primary rays of a `width` x `height` image row by row, and shades the
+    /// hits on the GPU into an RGBA f32 storage image, misses being transparent. Nothing is read
+    /// back to the host, for consumers that use the image in their own GPU work. Use `dispatch`
+    /// to get the results on the CPU instead. Blocks until the GPU is done.
+    pub fn dispatch_to_image(&self, data: &[MarchInstruction], width: u32, height: u32) -> Arc<StorageImage<Format>> {
+        assert_eq!(data.len(), (width * height) as usize, "one instruction per pixel");
+
+        let image = StorageImage::new(self.device.clone(), Dimensions::Dim2d { width, height },
+            Format::R32G32B32A32Sfloat, Some(self.queue.family())).unwrap();
+
+        let buffer = self.upload(data);
+        let march_set = Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+            .add_buffer(buffer.clone()).unwrap()
+            .build().unwrap()
+        );
+        let shade_set = Arc::new(PersistentDescriptorSet::start(self.shade_pipeline.clone(), 0)
+            .add_buffer(buffer).unwrap()
+            .add_image(image.clone()).unwrap()
+            .build().unwrap()
+        );
+
+        let groups = (data.len() as u32 + 63) / 64;
+
+        // The builder inserts the barrier between the two passes on the shared buffer.
+        let command_buffer = AutoCommandBufferBuilder::primary_one_time_submit(self.device.clone(), self.queue.family()).unwrap()
+            .dispatch([groups, 1, 1], self.pipeline.clone(), march_set, self.push_constants()).unwrap()
+            .dispatch([groups, 1, 1], self.shade_pipeline.clone(), shade_set, shade_cs::ty::PushConstants { width }).unwrap()
+            .build().unwrap();
+
+        sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), command_buffer).unwrap()
+            .then_signal_fence_and_flush().unwrap()
+            .wait(None).unwrap();
+
+        image
+    }
+
+    /// Copies `data` into a buffer the march shader can read and write.
+    fn upload(&self, data: &[MarchInstruction]) -> Arc<CpuAccessibleBuffer<[cs::ty::InputData]>> {
         let data_iter = data.iter().map(|i| cs::ty::InputData {
-				dir: i.direction,
-				origin: i.origin,
-				_dummy0: Default::default(),
-				_dummy1: Default::default(),
-			}
-		);
-        // Builds the buffer and fills it with this iterator.
-        CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), data_iter).unwrap()
-    };
+                dir: i.direction,
+                origin: i.origin,
+                position: [0.0; 3],
+                reflected_normal: [0.0; 3],
+                reflected_position: [0.0; 3],
+                _dummy0: Default::default(),
+                _dummy1: Default::default(),
+                _dummy2: Default::default(),
+                _dummy3: Default::default(),
+                _dummy4: Default::default(),
+            }
+        );
+        CpuAccessibleBuffer::from_iter(self.device.clone(), BufferUsage::all(), data_iter).unwrap()
+    }
 
-    let set = Arc::new(PersistentDescriptorSet::start(pipeline.clone(), 0)
-        .add_buffer(data_buffer.clone()).unwrap()
-        .build().unwrap()
-    );
+    fn push_constants(&self) -> cs::ty::PushConstants {
+        let (bounds_center, bounds_radius) = scene_bounding_sphere();
+        cs::ty::PushConstants { bounds_center, bounds_radius, reflectivity: self.reflectivity }
+    }
 
-    dbg!(data.len() / 64);
+    /// Uploads `data` and submits its command buffer without waiting for it to complete.
+    /// Returns `None` for an empty batch, there is nothing to submit.
+    fn submit(&self, data: &[MarchInstruction]) -> Option<InFlight> {
+        if data.is_empty() {
+            return None;
+        }
 
-    let command_buffer = AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family()).unwrap()
-        .dispatch([data.len() as u32 / 64, 1, 1], pipeline.clone(), set.clone(), ()).unwrap()
-        .build().unwrap();
+        // We start by creating the buffer that will store the data.
+        let buffer = self.upload(data);
 
-    let future = sync::now(device.clone())
-        .then_execute(queue.clone(), command_buffer).unwrap()
+        let set = Arc::new(PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+            .add_buffer(buffer.clone()).unwrap()
+            .build().unwrap()
+        );
 
-        // This line instructs the GPU to signal a *fence* once the command buffer has finished
-        // execution. A fence is a Vulkan object that allows the CPU to know when the GPU has
-        // reached a certain point.
-        // We need to signal a fence here because below we want to block the CPU until the GPU has
-        // reached that point in the execution.
-        .then_signal_fence_and_flush().unwrap();
+        // Round up, the shader skips the invocations past the end of the buffer.
+        let groups = (data.len() as u32 + 63) / 64;
 
-    // Blocks execution until the GPU has finished the operation. This method only exists on the
-    // future that corresponds to a signalled fence. In other words, this method wouldn't be
-    // available if we didn't call `.then_signal_fence_and_flush()` earlier.
-    // The `None` parameter is an optional timeout.
-    future.wait(None).unwrap();
+        let command_buffer = AutoCommandBufferBuilder::primary_one_time_submit(self.device.clone(), self.queue.family()).unwrap()
+            .dispatch([groups, 1, 1], self.pipeline.clone(), set.clone(), self.push_constants()).unwrap()
+            .build().unwrap();
 
-    // Now that the GPU is done, the content of the buffer should have been modified. Let's
-    // check it out.
-    // The call to `read()` would return an error if the buffer was still in use by the GPU.
-    let data_buffer_content = data_buffer.read().unwrap();
+        let future = sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), command_buffer).unwrap()
 
-    data_buffer_content.iter().map(|data| {
-        MarchResult {
-            distance: data.origin[0],
-            normal: data.dir,
+            // This line instructs the GPU to signal a *fence* once the command buffer has finished
+            // execution. A fence is a Vulkan object that allows the CPU to know when the GPU has
+            // reached a certain point.
+            // We need to signal a fence here because below we want to block the CPU until the GPU has
+            // reached that point in the execution.
+            .then_signal_fence_and_flush().unwrap();
+
+        Some(InFlight { buffer, future })
+    }
+}
+
+/// A batch that has been submitted to the GPU but not read back yet.
+struct InFlight {
+    buffer: Arc<CpuAccessibleBuffer<[cs::ty::InputData]>>,
+    future: FenceSignalFuture<CommandBufferExecFuture<NowFuture, AutoCommandBuffer>>,
+}
+
+impl InFlight {
+    fn finish(batch: Option<InFlight>) -> Vec<MarchResult> {
+        match batch {
+            Some(batch) => batch.wait(),
+            None => Vec::new(),
         }
-    }).collect()
+    }
+
+    fn wait(self) -> Vec<MarchResult> {
+        // Blocks execution until the GPU has finished the operation. This method only exists on the
+        // future that corresponds to a signalled fence. In other words, this method wouldn't be
+        // available if we didn't call `.then_signal_fence_and_flush()` earlier.
+        // The `None` parameter is an optional timeout.
+        self.future.wait(None).unwrap();
+
+        // Now that the GPU is done, the content of the buffer should have been modified. Let's
+        // check it out.
+        // The call to `read()` would return an error if the buffer was still in use by the GPU.
+        let data_buffer_content = self.buffer.read().unwrap();
+
+        data_buffer_content.iter().map(|data| {
+            MarchResult {
+                distance: data.origin[0],
+                normal: data.dir,
+                position: data.position,
+                steps: data.origin[1] as u32,
+                reflection: if data.origin[2] >= 0.0 {
+                    Some(ReflectionResult {
+                        distance: data.origin[2],
+                        normal: data.reflected_normal,
+                        position: data.reflected_position,
+                    })
+                } else {
+                    None
+                },
+            }
+        }).collect()
+    }
+}
+
+pub fn compute(data: &Vec<MarchInstruction>) -> Vec<MarchResult> {
+    Raymarcher::new().dispatch(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::prelude::*;
+
+    /// These tests need a Vulkan device, they pass without checking anything where there is none.
+    fn raymarcher() -> Option<Raymarcher> {
+        let instance = Instance::new(None, &InstanceExtensions::none(), None).ok()?;
+        PhysicalDevice::enumerate(&instance).next()?;
+        Some(Raymarcher::new())
+    }
+
+    fn ray(origin: [f32; 3], direction: [f32; 3]) -> MarchInstruction {
+        MarchInstruction { origin, direction }
+    }
+
+    #[test]
+    fn rays_stop_stepping_once_they_hit() {
+        let Some(gpu) = raymarcher() else { return };
+
+        // the second ray skims along the top of the mirror slab, creeping forward by the
+        // slab's distance every step until it runs out of them
+        let results = gpu.dispatch(&[
+            ray([0.0, 0.0, -10.0], [0.0, 0.0, 1.0]),
+            ray([-5.5, -1.89, 0.0], [1.0, 0.0, 0.0]),
+        ]);
+        let (head_on, grazing) = (results[0], results[1]);
+
+        assert!((head_on.distance - 8.5).abs() < 0.01);
+        assert_eq!(grazing.steps, MAX_STEPS);
+        assert!(head_on.steps < grazing.steps, "took {} steps", head_on.steps);
+    }
+
+    fn family(graphics: bool, compute: bool, transfer: bool) -> QueueFamilyCaps {
+        QueueFamilyCaps { graphics, compute, transfer }
+    }
+
+    #[test]
+    fn prefers_a_dedicated_transfer_family() {
+        let families = [family(true, true, true), family(false, true, true), family(false, false, true)];
+
+        assert_eq!(select_queue_families(&families), Some((0, Some(2))));
+    }
+
+    #[test]
+    fn falls_back_to_another_family_for_transfers() {
+        let families = [family(true, true, true), family(false, true, false)];
+
+        assert_eq!(select_queue_families(&families), Some((0, Some(1))));
+    }
+
+    #[test]
+    fn single_family_devices_share_the_compute_queue() {
+        assert_eq!(select_queue_families(&[family(true, true, true)]), Some((0, None)));
+        assert_eq!(select_queue_families(&[family(true, false, true)]), None);
+    }
+
+    /// Rays fanning out from in front of the sphere, some hitting it and some missing.
+    fn fan(count: usize) -> Vec<MarchInstruction> {
+        (0..count).map(|i| {
+            let x = i as f32 / count as f32 - 0.5;
+            let len = (x * x + 1.0).sqrt();
+            ray([0.0, 0.0, -10.0], [x / len, 0.0, 1.0 / len])
+        }).collect()
+    }
+
+    #[test]
+    fn results_follow_the_input_order() {
+        let Some(gpu) = raymarcher() else { return };
+
+        let rays = fan(200);
+        // a fixed permutation of the indices, 7 being coprime to 200
+        let order: Vec<usize> = (0..rays.len()).map(|i| i * 7 % rays.len()).collect();
+        let shuffled: Vec<_> = order.iter().map(|&i| rays[i]).collect();
+
+        let results = gpu.dispatch(&rays);
+        let shuffled_results = gpu.dispatch(&shuffled);
+
+        for (i, &original) in order.iter().enumerate() {
+            assert_eq!(shuffled_results[i].distance, results[original].distance);
+            assert_eq!(shuffled_results[i].steps, results[original].steps);
+        }
+    }
+
+    #[test]
+    fn batches_match_sequential_dispatches() {
+        let Some(gpu) = raymarcher() else { return };
+
+        let batches = vec![fan(64), fan(17), fan(100)];
+        let batched = gpu.dispatch_batches(&batches);
+
+        assert_eq!(batched.len(), batches.len());
+        for (batch, results) in batches.iter().zip(&batched) {
+            let sequential = gpu.dispatch(batch);
+
+            assert_eq!(results.len(), sequential.len());
+            for (a, b) in results.iter().zip(&sequential) {
+                assert_eq!(a.distance, b.distance);
+                assert_eq!(a.position, b.position);
+                assert_eq!(a.steps, b.steps);
+            }
+        }
+    }
+
+    #[test]
+    fn positions_lie_along_the_ray() {
+        let Some(gpu) = raymarcher() else { return };
+
+        let rays = fan(50);
+        let results = gpu.dispatch(&rays);
+
+        for (ray, result) in rays.iter().zip(&results) {
+            if result.distance >= MISS_DEPTH {
+                continue;
+            }
+
+            for axis in 0..3 {
+                let expected = ray.origin[axis] + ray.direction[axis] * result.distance;
+                assert!((result.position[axis] - expected).abs() < 1e-3, "{:?} vs {}", result.position, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn rays_outside_the_bounds_are_not_marched() {
+        let Some(gpu) = raymarcher() else { return };
+
+        // passes the scene at a distance of 10, far outside its bounds
+        let results = gpu.dispatch(&[ray([10.0, 0.0, -10.0], [0.0, 0.0, 1.0])]);
+
+        assert_eq!(results[0].distance, MISS_DEPTH);
+        assert_eq!(results[0].steps, 0);
+    }
+
+    #[test]
+    fn lists_the_device_the_raymarcher_uses() {
+        if raymarcher().is_none() {
+            return;
+        }
+
+        let devices = list_devices();
+
+        assert!(!devices.is_empty());
+        assert_eq!(devices[0].index, 0);
+        assert!(format!("{:?}", devices[0]).contains(&devices[0].name));
+    }
+
+    #[test]
+    fn image_output_matches_the_buffer_results() {
+        let Some(gpu) = raymarcher() else { return };
+
+        let (width, height) = (16u32, 8u32);
+        let rays: Vec<_> = (0..width * height).map(|i| {
+            let x = (i % width) as f32 / width as f32 * 0.5 - 0.25;
+            let y = (i / width) as f32 / height as f32 * 0.5 - 0.25;
+            let len = (x * x + y * y + 1.0).sqrt();
+            ray([0.0, 0.0, -10.0], [x / len, y / len, 1.0 / len])
+        }).collect();
+
+        // read the image back to compare it with what the buffer path returns
+        let image = gpu.dispatch_to_image(&rays, width, height);
+        let pixels = CpuAccessibleBuffer::from_iter(gpu.device.clone(), BufferUsage::all(), (0..width * height).map(|_| [0.0f32; 4])).unwrap();
+        let command_buffer = AutoCommandBufferBuilder::primary_one_time_submit(gpu.device.clone(), gpu.queue.family()).unwrap()
+            .copy_image_to_buffer(image, pixels.clone()).unwrap()
+            .build().unwrap();
+        sync::now(gpu.device.clone())
+            .then_execute(gpu.queue.clone(), command_buffer).unwrap()
+            .then_signal_fence_and_flush().unwrap()
+            .wait(None).unwrap();
+        let pixels = pixels.read().unwrap();
+
+        for (pixel, result) in pixels.iter().zip(gpu.dispatch(&rays)) {
+            if result.distance >= MISS_DEPTH {
+                assert_eq!(*pixel, [0.0; 4]);
+                continue;
+            }
+
+            // the shading of the image pass, applied to the buffer results
+            let p = result.position;
+            let to_light = [4.0 - p[0], 3.0 - p[1], -6.0 - p[2]];
+            let length_squared: f32 = to_light.iter().map(|v| v * v).sum();
+            let cos_theta = (0..3).map(|i| to_light[i] * result.normal[i]).sum::<f32>().max(0.0) / length_squared.sqrt();
+            let red = cos_theta * 10.0 / length_squared + 0.04;
+
+            assert!((pixel[0] - red).abs() < 1e-3, "{:?} vs {}", pixel, red);
+            assert!((pixel[1] - 0.04).abs() < 1e-3 && (pixel[2] - 0.04).abs() < 1e-3);
+            assert_eq!(pixel[3], 1.0);
+        }
+    }
+
+    #[test]
+    fn uploads_get_their_own_queue_where_there_is_a_family_for_it() {
+        let Some(gpu) = raymarcher() else { return };
+
+        let caps: Vec<_> = gpu.device.physical_device().queue_families().map(|f| QueueFamilyCaps {
+            graphics: f.supports_graphics(),
+            compute: f.supports_compute(),
+            transfer: f.explicitly_supports_transfers(),
+        }).collect();
+        let (compute, transfer) = select_queue_families(&caps).unwrap();
+
+        assert_eq!(gpu.queue.family().id() as usize, compute);
+        assert_eq!(gpu.transfer_queue().family().id() as usize, transfer.unwrap_or(compute));
+    }
 }